@@ -0,0 +1,283 @@
+use crate::{StreamingCallbackHttpResponse, StreamingHttpResponse, StreamingToken};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ic_cdk::api::{data_certificate, set_certified_data};
+use ic_certification::{empty, fork, labeled, leaf, pruned, HashTree};
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseOnlyCelExpression, HttpCertification, HttpCertificationPath,
+    HttpCertificationTree, HttpCertificationTreeEntry, HttpRequest, HttpResponse,
+    CERTIFICATE_EXPRESSION_HEADER_NAME,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+const IC_CERTIFICATE_HEADER: &str = "IC-Certificate";
+
+thread_local! {
+    /// The v2 certifications of all responses, shared with the asset router.
+    pub static HTTP_TREE: Rc<RefCell<HttpCertificationTree>> = Default::default();
+
+    /// The SHA-256 hashes of the bodies of responses certified with v1, by path.
+    static V1_ASSETS: RefCell<BTreeMap<String, [u8; 32]>> = Default::default();
+
+    static CERTIFIED_RESPONSES: RefCell<Vec<CertifiedResponse>> = Default::default();
+}
+
+/// How a response is certified.
+pub enum ResponseCertification<'a> {
+    /// Response verification v1, which only certifies the body.
+    V1,
+
+    /// Response verification v2, certifiably skipping verification.
+    Skip,
+
+    /// Response verification v2, certifying the response only.
+    ResponseOnly(DefaultResponseOnlyCelExpression<'a>),
+}
+
+/// A response served for requests to a path that is certified when the canister is installed.
+struct CertifiedResponse {
+    path: String,
+    /// The content encoding of the body, served to clients that accept it.
+    encoding: Option<String>,
+    response: HttpResponse,
+    streamed_chunks: Vec<Vec<u8>>,
+    /// The v2 certification of the response, or `None` if it is certified with v1.
+    certification: Option<HttpCertification>,
+}
+
+/// Certifies `response` for requests to `path`. If there are `streamed_chunks`, the body of `response`
+/// is the first chunk and the others are returned by the streaming callback, and the certification
+/// covers all of them.
+pub fn certify_response(
+    path: &str,
+    encoding: Option<&str>,
+    mut response: HttpResponse,
+    streamed_chunks: Vec<Vec<u8>>,
+    certification: ResponseCertification,
+) {
+    let full_body = [response.body.clone(), streamed_chunks.concat()].concat();
+
+    let certification = match certification {
+        ResponseCertification::V1 => {
+            V1_ASSETS.with_borrow_mut(|v1_assets| {
+                v1_assets.insert(path.to_string(), Sha256::digest(&full_body).into());
+            });
+
+            None
+        }
+        ResponseCertification::Skip => {
+            response.headers.push((
+                CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+                DefaultCelBuilder::skip_certification().to_string(),
+            ));
+
+            Some(HttpCertification::skip())
+        }
+        ResponseCertification::ResponseOnly(cel_expr) => {
+            response.headers.push((
+                CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+                cel_expr.to_string(),
+            ));
+            let full_response = HttpResponse {
+                body: full_body,
+                ..response.clone()
+            };
+
+            Some(
+                HttpCertification::response_only(&cel_expr, &full_response, None).unwrap_or_else(
+                    |err| ic_cdk::trap(&format!("Failed to certify {}: {}", path, err)),
+                ),
+            )
+        }
+    };
+
+    if let Some(certification) = &certification {
+        let certification_path = HttpCertificationPath::exact(path);
+        HTTP_TREE.with(|http_tree| {
+            http_tree
+                .borrow_mut()
+                .insert(&HttpCertificationTreeEntry::new(
+                    &certification_path,
+                    certification,
+                ))
+        });
+    }
+
+    CERTIFIED_RESPONSES.with_borrow_mut(|certified_responses| {
+        certified_responses.push(CertifiedResponse {
+            path: path.to_string(),
+            encoding: encoding.map(str::to_string),
+            response,
+            streamed_chunks,
+            certification,
+        })
+    });
+}
+
+/// Sets the certified data of the canister to the root hash of the v1 and v2 certifications.
+pub fn set_root_hash() {
+    let root_hash = fork(v1_tree(None), pruned(http_tree_root_hash())).digest();
+
+    set_certified_data(&root_hash);
+}
+
+/// Serves the certified response for `request`, preferring encoded variants that the client accepts.
+pub fn serve_certified_response(request: &HttpRequest) -> Option<StreamingHttpResponse> {
+    let path = request_path(&request.url);
+    let accept_encoding = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+        .map(|(_, value)| value.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    CERTIFIED_RESPONSES.with_borrow(|certified_responses| {
+        let (response_index, certified_response) = certified_responses
+            .iter()
+            .enumerate()
+            .filter(|(_, certified_response)| certified_response.path == path)
+            .filter(|(_, certified_response)| {
+                certified_response
+                    .encoding
+                    .as_ref()
+                    .map_or(true, |encoding| accept_encoding.contains(encoding.as_str()))
+            })
+            .max_by_key(|(_, certified_response)| certified_response.encoding.is_some())?;
+
+        let mut response = certified_response.response.clone();
+        let certificate_header = match &certified_response.certification {
+            Some(certification) => v2_certificate_header(path, &request.url, certification),
+            None => v1_certificate_header(path),
+        };
+        response
+            .headers
+            .push((IC_CERTIFICATE_HEADER.to_string(), certificate_header));
+
+        if certified_response.streamed_chunks.is_empty() {
+            Some(response.into())
+        } else {
+            Some(StreamingHttpResponse::streamed(
+                response,
+                response_index as u32,
+            ))
+        }
+    })
+}
+
+/// Returns the chunk of a streamed response identified by `token`.
+pub fn streamed_chunk(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    CERTIFIED_RESPONSES.with_borrow(|certified_responses| {
+        let chunks = certified_responses
+            .get(token.response_index as usize)
+            .map(|certified_response| &certified_response.streamed_chunks)
+            .unwrap_or_else(|| ic_cdk::trap("Invalid streaming token"));
+        let chunk_index = token.chunk_index as usize;
+        let body = chunks
+            .get(chunk_index)
+            .unwrap_or_else(|| ic_cdk::trap("Invalid streaming token"))
+            .clone();
+
+        StreamingCallbackHttpResponse {
+            body,
+            token: (chunk_index + 1 < chunks.len()).then(|| StreamingToken {
+                chunk_index: token.chunk_index + 1,
+                ..token
+            }),
+        }
+    })
+}
+
+/// Adds the `IC-Certificate` header to a response served by the asset router,
+/// whose `witness` only covers the v2 certifications.
+pub fn add_certificate_header(
+    response: &mut HttpResponse,
+    witness: &HashTree,
+    expr_path: &[String],
+) {
+    let tree = fork(pruned(v1_tree(None).digest()), witness.clone());
+
+    response.headers.push((
+        IC_CERTIFICATE_HEADER.to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            BASE64.encode(certificate()),
+            BASE64.encode(cbor_encode(&tree)),
+            BASE64.encode(cbor_encode(&expr_path))
+        ),
+    ));
+}
+
+fn v1_certificate_header(path: &str) -> String {
+    let tree = fork(v1_tree(Some(path)), pruned(http_tree_root_hash()));
+
+    format!(
+        "certificate=:{}:, tree=:{}:",
+        BASE64.encode(certificate()),
+        BASE64.encode(cbor_encode(&tree))
+    )
+}
+
+fn v2_certificate_header(path: &str, url: &str, certification: &HttpCertification) -> String {
+    let certification_path = HttpCertificationPath::exact(path);
+    let entry = HttpCertificationTreeEntry::new(&certification_path, certification);
+    let witness = HTTP_TREE.with(|http_tree| {
+        http_tree
+            .borrow()
+            .witness(&entry, url)
+            .unwrap_or_else(|err| ic_cdk::trap(&format!("Failed to witness {}: {}", path, err)))
+    });
+    let tree = fork(pruned(v1_tree(None).digest()), witness);
+
+    format!(
+        "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+        BASE64.encode(certificate()),
+        BASE64.encode(cbor_encode(&tree)),
+        BASE64.encode(cbor_encode(&certification_path.to_expr_path()))
+    )
+}
+
+/// Returns the `http_assets` subtree of v1 certifications, pruning all paths but `witness_path`.
+/// If there is no `witness_path`, the entire subtree is returned.
+fn v1_tree(witness_path: Option<&str>) -> HashTree {
+    V1_ASSETS.with_borrow(|v1_assets| {
+        let tree = v1_assets
+            .iter()
+            .map(|(path, body_hash)| {
+                let node = labeled(path.as_bytes(), leaf(body_hash.to_vec()));
+
+                match witness_path {
+                    Some(witness_path) if witness_path != path => pruned(node.digest()),
+                    _ => node,
+                }
+            })
+            .reduce(fork)
+            .unwrap_or_else(empty);
+
+        labeled("http_assets", tree)
+    })
+}
+
+fn http_tree_root_hash() -> [u8; 32] {
+    HTTP_TREE.with(|http_tree| http_tree.borrow().root_hash())
+}
+
+fn certificate() -> Vec<u8> {
+    data_certificate().expect("No data certificate available")
+}
+
+/// Returns the path of `url`, without the query string.
+pub fn request_path(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(path, _)| path)
+}
+
+fn cbor_encode(value: &impl Serialize) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(Vec::new());
+    serializer
+        .self_describe()
+        .expect("Failed to self describe CBOR");
+    value
+        .serialize(&mut serializer)
+        .expect("Failed to serialize value");
+    serializer.into_inner()
+}
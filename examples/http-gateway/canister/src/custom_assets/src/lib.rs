@@ -1,18 +1,32 @@
-use base64::engine::general_purpose::STANDARD as BASE64;
-use base64::Engine;
 use ic_asset_certification::{Asset, AssetConfig, AssetFallbackConfig, AssetRouter};
-use ic_cdk::{
-    api::{data_certificate, set_certified_data},
-    *,
+use ic_cdk::{api::call::ManualReply, *};
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseCertification, HeaderField, HttpRequest, HttpResponse,
 };
-use ic_certification::HashTree;
-use ic_http_certification::{HeaderField, HttpRequest, HttpResponse};
-use serde::Serialize;
-use std::cell::RefCell;
+use std::{cell::RefCell, rc::Rc};
+
+mod certification;
+use certification::*;
+
+mod streaming;
+use streaming::*;
+
+/// The size of the chunks of the large streamed asset, which is larger than the
+/// gateway verifies by default, so that it is streamed to the client without verification.
+const LARGE_ASSET_CHUNK_SIZE: usize = 256 * 1024;
+
+const LARGE_ASSET_CHUNK_COUNT: usize = 6;
+
+/// `<html><body>Hello, encoded world!</body></html>`, compressed with gzip.
+const GZIP_ENCODED_BODY: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xb3\xc9\x28\xc9\xcd\xb1\xb3\x49\xca\x4f\xa9\xb4\xf3\x48\xcd\xc9\xc9\xd7\x51\x48\xcd\x4b\xce\x4f\x49\x4d\x51\x28\xcf\x2f\xca\x49\x51\xb4\xd1\x07\xcb\xd9\xe8\x83\x15\x02\x00\x20\xef\xee\x20\x2f\x00\x00\x00";
+
+const ENCODED_BODY: &[u8] = b"<html><body>Hello, encoded world!</body></html>";
 
 #[init]
 fn init() {
     certify_all_assets();
+    certify_all_responses();
+    set_root_hash();
 }
 
 #[post_upgrade]
@@ -20,13 +34,46 @@ fn post_upgrade() {
     init();
 }
 
+#[query(manual_reply = true)]
+fn http_request(req: HttpRequest) -> ManualReply<StreamingHttpResponse> {
+    match request_path(&req.url) {
+        // uncertified responses that ask the gateway to upgrade to an update call
+        "/upgrade" | "/upgrade/reject" => {
+            ManualReply::one(StreamingHttpResponse::from(HttpResponse {
+                upgrade: Some(true),
+                ..response(200, vec![], b"")
+            }))
+        }
+        "/uncertified" => ManualReply::one(StreamingHttpResponse::from(response(
+            200,
+            vec![],
+            b"uncertified",
+        ))),
+        "/reject" => ManualReply::reject("rejected by the canister"),
+        "/trap" => ic_cdk::trap("trapped by the canister"),
+        _ => ManualReply::one(
+            serve_certified_response(&req).unwrap_or_else(|| serve_asset(&req).into()),
+        ),
+    }
+}
+
+#[update]
+fn http_request_update(req: HttpRequest) -> StreamingHttpResponse {
+    match request_path(&req.url) {
+        "/upgrade" => response(201, vec![], b"updated").into(),
+        "/upgrade/reject" => ic_cdk::trap("canister trapped"),
+        _ => response(404, vec![], b"not found").into(),
+    }
+}
+
 #[query]
-fn http_request(req: HttpRequest) -> HttpResponse {
-    serve_asset(&req)
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    streamed_chunk(token)
 }
 
 thread_local! {
-    static ASSET_ROUTER: RefCell<AssetRouter<'static>> = Default::default();
+    static ASSET_ROUTER: RefCell<AssetRouter<'static>> =
+        RefCell::new(AssetRouter::with_tree(HTTP_TREE.with(Rc::clone)));
 }
 
 fn certify_all_assets() {
@@ -52,11 +99,155 @@ fn certify_all_assets() {
         if let Err(err) = asset_router.certify_assets(assets, asset_configs) {
             ic_cdk::trap(&format!("Failed to certify assets: {}", err));
         }
-
-        set_certified_data(&asset_router.root_hash());
     });
 }
 
+/// Certifies the responses that exercise the different verification versions, certification
+/// options and streaming of the gateway.
+fn certify_all_responses() {
+    let certify_all_headers = DefaultCelBuilder::response_only_certification()
+        .with_response_certification(DefaultResponseCertification::response_header_exclusions(
+            vec![],
+        ))
+        .build();
+
+    certify_response(
+        "/v1",
+        None,
+        response(
+            200,
+            vec![
+                header("x-custom", "value"),
+                header("cache-control", "max-age=31536000"),
+            ],
+            b"certified with v1",
+        ),
+        vec![],
+        ResponseCertification::V1,
+    );
+    certify_response(
+        "/v1/redirect",
+        None,
+        response(302, vec![header("location", "https://example.com")], b""),
+        vec![],
+        ResponseCertification::V1,
+    );
+    certify_response(
+        "/v2/redirect",
+        None,
+        response(302, vec![header("location", "https://example.com")], b""),
+        vec![],
+        ResponseCertification::ResponseOnly(certify_all_headers.clone()),
+    );
+    certify_response(
+        "/v2/headers",
+        None,
+        response(
+            200,
+            vec![
+                header("x-certified", "value"),
+                header("x-uncertified", "value"),
+            ],
+            b"certified with v2",
+        ),
+        vec![],
+        ResponseCertification::ResponseOnly(
+            DefaultCelBuilder::response_only_certification()
+                .with_response_certification(
+                    DefaultResponseCertification::response_header_exclusions(vec!["x-uncertified"]),
+                )
+                .build(),
+        ),
+    );
+    certify_response(
+        "/v2/skip",
+        None,
+        response(
+            200,
+            vec![
+                header("x-custom", "value"),
+                header("keep-alive", "timeout=5"),
+            ],
+            b"not certified",
+        ),
+        vec![],
+        ResponseCertification::Skip,
+    );
+    certify_response(
+        "/no-upgrade",
+        None,
+        HttpResponse {
+            upgrade: Some(false),
+            ..response(200, vec![], b"query")
+        },
+        vec![],
+        ResponseCertification::ResponseOnly(certify_all_headers.clone()),
+    );
+
+    for path in ["/stream", "/stream/unverified", "/stream/too-large"] {
+        certify_response(
+            path,
+            None,
+            response(200, vec![], b"one,"),
+            vec![b"two,".to_vec(), b"three".to_vec()],
+            ResponseCertification::ResponseOnly(certify_all_headers.clone()),
+        );
+    }
+    certify_response(
+        "/stream/large",
+        None,
+        response(
+            200,
+            vec![header("content-type", "application/octet-stream")],
+            &large_asset_chunk(0),
+        ),
+        (1..LARGE_ASSET_CHUNK_COUNT)
+            .map(large_asset_chunk)
+            .collect(),
+        ResponseCertification::ResponseOnly(certify_all_headers.clone()),
+    );
+
+    certify_response(
+        "/encoded",
+        None,
+        response(200, vec![header("content-type", "text/html")], ENCODED_BODY),
+        vec![],
+        ResponseCertification::ResponseOnly(certify_all_headers.clone()),
+    );
+    certify_response(
+        "/encoded",
+        Some("gzip"),
+        response(
+            200,
+            vec![
+                header("content-type", "text/html"),
+                header("content-encoding", "gzip"),
+            ],
+            GZIP_ENCODED_BODY,
+        ),
+        vec![],
+        ResponseCertification::ResponseOnly(certify_all_headers),
+    );
+}
+
+/// Returns a chunk of the large streamed asset, in which every byte is the index of its chunk.
+fn large_asset_chunk(index: usize) -> Vec<u8> {
+    vec![index as u8; LARGE_ASSET_CHUNK_SIZE]
+}
+
+fn response(status_code: u16, headers: Vec<HeaderField>, body: &[u8]) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers,
+        body: body.to_vec(),
+        upgrade: None,
+    }
+}
+
+fn header(name: &str, value: &str) -> HeaderField {
+    (name.to_string(), value.to_string())
+}
+
 fn serve_asset(req: &HttpRequest) -> HttpResponse {
     ASSET_ROUTER.with_borrow(|asset_router| {
         if let Ok((mut response, witness, expr_path)) = asset_router.serve_asset(req) {
@@ -84,31 +275,3 @@ fn get_asset_headers(additional_headers: Vec<HeaderField>) -> Vec<HeaderField> {
 
     headers
 }
-
-const IC_CERTIFICATE_HEADER: &str = "IC-Certificate";
-fn add_certificate_header(response: &mut HttpResponse, witness: &HashTree, expr_path: &[String]) {
-    let certified_data = data_certificate().expect("No data certificate available");
-    let witness = cbor_encode(witness);
-    let expr_path = cbor_encode(&expr_path);
-
-    response.headers.push((
-        IC_CERTIFICATE_HEADER.to_string(),
-        format!(
-            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
-            BASE64.encode(certified_data),
-            BASE64.encode(witness),
-            BASE64.encode(expr_path)
-        ),
-    ));
-}
-
-fn cbor_encode(value: &impl Serialize) -> Vec<u8> {
-    let mut serializer = serde_cbor::Serializer::new(Vec::new());
-    serializer
-        .self_describe()
-        .expect("Failed to self describe CBOR");
-    value
-        .serialize(&mut serializer)
-        .expect("Failed to serialize value");
-    serializer.into_inner()
-}
@@ -0,0 +1,69 @@
+use candid::{define_function, CandidType, Deserialize};
+use ic_http_certification::{HeaderField, HttpResponse};
+
+pub const STREAMING_CALLBACK_METHOD: &str = "http_request_streaming_callback";
+
+/// Identifies the next chunk of a streamed response.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingToken {
+    pub response_index: u32,
+    pub chunk_index: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingToken>,
+}
+
+define_function!(pub StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+/// The response of `http_request` and `http_request_update`, which unlike [HttpResponse]
+/// can stream its body using a callback.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingHttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+impl StreamingHttpResponse {
+    /// Streams the chunks of the response at `response_index` after the body of `response`.
+    pub fn streamed(response: HttpResponse, response_index: u32) -> Self {
+        Self {
+            streaming_strategy: Some(StreamingStrategy::Callback {
+                callback: StreamingCallback::new(
+                    ic_cdk::id(),
+                    STREAMING_CALLBACK_METHOD.to_string(),
+                ),
+                token: StreamingToken {
+                    response_index,
+                    chunk_index: 0,
+                },
+            }),
+            ..response.into()
+        }
+    }
+}
+
+impl From<HttpResponse> for StreamingHttpResponse {
+    fn from(response: HttpResponse) -> Self {
+        Self {
+            status_code: response.status_code,
+            headers: response.headers,
+            body: response.body,
+            streaming_strategy: None,
+            upgrade: response.upgrade,
+        }
+    }
+}
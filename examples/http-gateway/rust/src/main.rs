@@ -1,8 +1,10 @@
 use http_body_util::BodyExt;
 use hyper::{body::Incoming, server::conn::http2, service::service_fn, Request, Response};
 use hyper_util::rt::TokioIo;
-use ic_agent::Agent;
-use ic_http_gateway::{HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseBody};
+use ic_http_gateway::{
+    ClientInfo, EndpointPool, HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseBody,
+    RootKey,
+};
 use pocket_ic::PocketIcBuilder;
 use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::{fs::File, io::AsyncReadExt, net::TcpListener, task};
@@ -44,13 +46,17 @@ fn main() {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
+    // the pool's health checks keep the latency of its endpoints up to date,
+    // endpoints that failed a request recover on their own after the health check interval
+    let endpoint_pool = EndpointPool::new([url]).unwrap();
+    rt.spawn({
+        let endpoint_pool = endpoint_pool.clone();
+        async move { endpoint_pool.run_health_checks().await }
     });
 
     let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
+        .with_endpoint_pool(endpoint_pool)
+        .with_root_key(RootKey::InsecureFetchFromDevNetwork)
         .build()
         .unwrap();
 
@@ -61,7 +67,7 @@ fn main() {
         println!("Listening on: {}", addr);
 
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
+            let (stream, peer_addr) = listener.accept().await.unwrap();
             let io = TokioIo::new(stream);
 
             let http_gateway_clone = Arc::new(http_gateway.clone());
@@ -78,6 +84,12 @@ fn main() {
                         .request(HttpGatewayRequestArgs {
                             canister_id,
                             canister_request,
+                            client_info: Some(ClientInfo {
+                                ip: peer_addr.ip(),
+                                scheme: "http".to_string(),
+                                host: None,
+                            }),
+                            identity: None,
                         })
                         .send()
                         .await;
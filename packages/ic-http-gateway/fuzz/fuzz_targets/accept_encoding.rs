@@ -0,0 +1,13 @@
+#![no_main]
+
+use ic_http_gateway::testing::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|accept_encoding: &str| {
+    let value = fuzzing::add_identity_encoding(accept_encoding);
+
+    assert!(value
+        .split(',')
+        .any(|encoding| encoding.trim().eq_ignore_ascii_case("identity")));
+    assert_eq!(fuzzing::add_identity_encoding(&value), value);
+});
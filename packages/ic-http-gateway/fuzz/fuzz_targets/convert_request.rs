@@ -0,0 +1,72 @@
+#![no_main]
+
+use http::{HeaderValue, Request};
+use ic_http_gateway::{testing::fuzzing, InvalidHeaderValueAction, UrlNormalization};
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    method: String,
+    uri: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    normalize_url: bool,
+    transcode_header_values: bool,
+}
+
+fuzz_target!(|input: Input| {
+    let mut request = Request::builder()
+        .method(input.method.as_str())
+        .uri(input.uri.as_str());
+    let mut headers = vec![];
+    for (name, value) in &input.headers {
+        if let Ok(value) = HeaderValue::from_bytes(value) {
+            request = request.header(name.as_str(), value.clone());
+            headers.push((name.to_ascii_lowercase(), value));
+        }
+    }
+    let Ok(request) = request.body(input.body.clone()) else {
+        return;
+    };
+    let uri = request.uri().clone();
+
+    let url_normalization = if input.normalize_url {
+        UrlNormalization::Rfc3986
+    } else {
+        UrlNormalization::Disabled
+    };
+    let invalid_header_value_action = if input.transcode_header_values {
+        InvalidHeaderValueAction::Transcode
+    } else {
+        InvalidHeaderValueAction::Reject
+    };
+
+    let Ok(http_request) =
+        fuzzing::convert_request(request, url_normalization, invalid_header_value_action)
+    else {
+        return;
+    };
+
+    assert_eq!(http_request.body, input.body);
+
+    // without normalization, the URL and visible ASCII headers are forwarded byte-for-byte
+    if url_normalization == UrlNormalization::Disabled {
+        let expected_url = match uri.query() {
+            Some(query) => format!("{}?{}", uri.path(), query),
+            None => uri.path().to_string(),
+        };
+        assert_eq!(http_request.url, expected_url);
+
+        if headers.iter().all(|(_, value)| value.to_str().is_ok()) {
+            let mut expected_headers = headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.to_str().unwrap().to_string()))
+                .collect::<Vec<_>>();
+            let mut actual_headers = http_request.headers;
+            expected_headers.sort();
+            actual_headers.sort();
+
+            assert_eq!(actual_headers, expected_headers);
+        }
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use http::StatusCode;
+use ic_http_gateway::testing::fuzzing;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (u16, &str)| {
+    let (status_code, msg) = input;
+    let Ok(status_code) = StatusCode::from_u16(status_code) else {
+        return;
+    };
+
+    let response = fuzzing::create_err_response(status_code, msg);
+
+    assert_eq!(response.status(), status_code);
+});
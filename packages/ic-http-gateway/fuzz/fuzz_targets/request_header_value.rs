@@ -0,0 +1,42 @@
+#![no_main]
+
+use http::HeaderValue;
+use ic_http_gateway::{testing::fuzzing, InvalidHeaderValueAction};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: &[u8]| {
+    let Ok(header_value) = HeaderValue::from_bytes(bytes) else {
+        return;
+    };
+
+    let rejected = fuzzing::convert_request_header_value(
+        "x-header",
+        &header_value,
+        InvalidHeaderValueAction::Reject,
+    );
+    let dropped = fuzzing::convert_request_header_value(
+        "x-header",
+        &header_value,
+        InvalidHeaderValueAction::Drop,
+    )
+    .unwrap();
+    let transcoded = fuzzing::convert_request_header_value(
+        "x-header",
+        &header_value,
+        InvalidHeaderValueAction::Transcode,
+    )
+    .unwrap()
+    .unwrap();
+
+    match header_value.to_str() {
+        Ok(value) => {
+            assert_eq!(rejected.unwrap().as_deref(), Some(value));
+            assert_eq!(dropped.as_deref(), Some(value));
+            assert_eq!(transcoded, value);
+        }
+        Err(_) => {
+            assert!(rejected.is_err());
+            assert!(dropped.is_none());
+        }
+    }
+});
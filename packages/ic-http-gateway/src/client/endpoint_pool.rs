@@ -0,0 +1,525 @@
+use crate::{is_mainnet_url, HttpGatewayError, HttpGatewayResult, RootKey};
+use futures::future::join_all;
+use ic_agent::{Agent, AgentError};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The weight of the most recent health check in the moving average of an endpoint's latency.
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+const HEALTHY_REPLICA_STATUS: &str = "healthy";
+
+/// A pool of API boundary node endpoints that requests are routed to.
+///
+/// The endpoints are health checked using their status endpoint, requests are routed to
+/// the healthy endpoint with the lowest latency, and requests that fail with a transport error
+/// before any update call was made are retried on the next best endpoint.
+///
+/// The pool does not spawn any tasks itself. Health checks run periodically once the future returned
+/// by [run_health_checks](EndpointPool::run_health_checks) is spawned on the runtime of the caller,
+/// and they can also be run once using [check_health](EndpointPool::check_health).
+/// Until the endpoints are health checked, all of them are considered healthy. An endpoint that
+/// failed a health check or a request is considered healthy again once the health check interval
+/// and timeout have passed, so that endpoints recover even if no health checks are run.
+#[derive(Clone)]
+pub struct EndpointPool {
+    endpoints: Arc<[Endpoint]>,
+    health_check_interval: Duration,
+    health_check_timeout: Duration,
+    fetch_root_key: bool,
+}
+
+struct Endpoint {
+    url: Option<String>,
+    agent: Agent,
+    health: Mutex<EndpointHealth>,
+    root_key_fetched: OnceCell<()>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EndpointHealth {
+    /// When the endpoint last failed a health check or a request, unless it passed a health check since.
+    unhealthy_since: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+impl EndpointPool {
+    /// Creates a pool with an agent for each of the given endpoint URLs.
+    pub fn new(urls: impl IntoIterator<Item = impl Into<String>>) -> HttpGatewayResult<Self> {
+        let endpoints = urls
+            .into_iter()
+            .map(|url| {
+                let url = url.into();
+                let agent = Agent::builder().with_url(url.as_str()).build()?;
+
+                Ok(Endpoint::new(Some(url), agent))
+            })
+            .collect::<HttpGatewayResult<Vec<_>>>()?;
+
+        if endpoints.is_empty() {
+            return Err(HttpGatewayError::EmptyEndpointPool);
+        }
+
+        Ok(Self::from_endpoints(endpoints))
+    }
+
+    /// Creates a pool that routes all requests through the given agent.
+    pub fn from_agent(agent: Agent) -> Self {
+        Self::from_endpoints(vec![Endpoint::new(None, agent)])
+    }
+
+    fn from_endpoints(endpoints: Vec<Endpoint>) -> Self {
+        Self {
+            endpoints: endpoints.into(),
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            health_check_timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            fetch_root_key: false,
+        }
+    }
+
+    /// Sets how often the endpoints are health checked.
+    pub fn with_health_check_interval(mut self, health_check_interval: Duration) -> Self {
+        self.health_check_interval = health_check_interval;
+
+        self
+    }
+
+    /// Sets how long to wait for the status of an endpoint before considering it unhealthy.
+    pub fn with_health_check_timeout(mut self, health_check_timeout: Duration) -> Self {
+        self.health_check_timeout = health_check_timeout;
+
+        self
+    }
+
+    /// Fetches the status of all endpoints and updates their health and latency.
+    pub async fn check_health(&self) {
+        join_all(self.endpoints.iter().map(|endpoint| async move {
+            let start = Instant::now();
+            let status =
+                tokio::time::timeout(self.health_check_timeout, endpoint.agent.status()).await;
+            let latency = start.elapsed();
+
+            let healthy = matches!(
+                status,
+                Ok(Ok(status)) if status
+                    .replica_health_status
+                    .as_deref()
+                    .map_or(true, |replica_health_status| {
+                        replica_health_status == HEALTHY_REPLICA_STATUS
+                    })
+            );
+
+            endpoint.record_health_check(healthy, latency);
+        }))
+        .await;
+    }
+
+    /// Health checks the endpoints every health check interval, until the returned future is dropped.
+    /// Spawn it on a clone of the pool, clones share the health of the endpoints.
+    /// Pools with a single endpoint are never health checked, so the future completes immediately.
+    pub async fn run_health_checks(&self) {
+        if self.endpoints.len() < 2 {
+            return;
+        }
+
+        loop {
+            self.check_health().await;
+            tokio::time::sleep(self.health_check_interval).await;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    pub(crate) fn agent(&self, index: usize) -> &Agent {
+        &self.endpoints[index].agent
+    }
+
+    pub(crate) fn url(&self, index: usize) -> Option<&str> {
+        self.endpoints[index].url.as_deref()
+    }
+
+    /// Selects the endpoint to route a request to, skipping the `excluded` endpoints.
+    /// Healthy endpoints are preferred over unhealthy ones and endpoints whose latency is not known
+    /// yet are preferred over the others, so that their latency is measured.
+    /// Returns `None` if all endpoints are excluded.
+    pub(crate) fn select(&self, excluded: &[usize]) -> Option<usize> {
+        let recovery_period = self.health_check_interval + self.health_check_timeout;
+
+        self.endpoints
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !excluded.contains(index))
+            .map(|(index, endpoint)| (index, *endpoint.health.lock().unwrap()))
+            .min_by_key(|(_, health)| (!health.is_healthy(recovery_period), health.latency))
+            .map(|(index, _)| index)
+    }
+
+    /// Configures the agents of the pool to verify certificates against `root_key`,
+    /// or against the mainnet root key if no root key was configured.
+    ///
+    /// Fails if the mainnet root key is used with an endpoint that is not part of mainnet,
+    /// or if the root key would be fetched from an endpoint that is part of mainnet.
+    /// The URL of agents that were created outside of the pool is unknown, so they can only be used
+    /// if a root key was configured explicitly, and their root key is never fetched.
+    pub(crate) fn set_root_key(&mut self, root_key: Option<RootKey>) -> HttpGatewayResult<()> {
+        let is_configured = root_key.is_some();
+
+        match root_key.unwrap_or_default() {
+            RootKey::Mainnet => {
+                for endpoint in self.endpoints.iter() {
+                    match endpoint.url.as_deref() {
+                        Some(url) if !is_mainnet_url(url) => {
+                            return Err(HttpGatewayError::RootKeyRequired {
+                                endpoint: url.to_string(),
+                            });
+                        }
+                        None if !is_configured => {
+                            return Err(HttpGatewayError::AgentRootKeyRequired);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            RootKey::Pinned(root_key) => {
+                for endpoint in self.endpoints.iter() {
+                    endpoint.agent.set_root_key(root_key.clone());
+                }
+            }
+            RootKey::InsecureFetchFromDevNetwork => {
+                for endpoint in self.endpoints.iter() {
+                    match endpoint.url.as_deref() {
+                        Some(url) if is_mainnet_url(url) => {
+                            return Err(HttpGatewayError::InsecureRootKeyFetch {
+                                endpoint: url.to_string(),
+                            });
+                        }
+                        None => return Err(HttpGatewayError::AgentRootKeyFetch),
+                        _ => {}
+                    }
+                }
+
+                self.fetch_root_key = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the root key of an endpoint if the pool is configured to do so
+    /// and the root key of the endpoint has not been fetched yet.
+    pub(crate) async fn fetch_root_key_if_needed(&self, index: usize) -> Result<(), AgentError> {
+        if !self.fetch_root_key {
+            return Ok(());
+        }
+
+        let endpoint = &self.endpoints[index];
+        endpoint
+            .root_key_fetched
+            .get_or_try_init(|| endpoint.agent.fetch_root_key())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Marks an endpoint as unhealthy until its next successful health check,
+    /// or until the health check interval and timeout have passed.
+    pub(crate) fn mark_unhealthy(&self, index: usize) {
+        self.endpoints[index].health.lock().unwrap().unhealthy_since = Some(Instant::now());
+    }
+}
+
+impl Endpoint {
+    fn new(url: Option<String>, agent: Agent) -> Self {
+        Self {
+            url,
+            agent,
+            health: Mutex::new(EndpointHealth {
+                unhealthy_since: None,
+                latency: None,
+            }),
+            root_key_fetched: OnceCell::new(),
+        }
+    }
+
+    fn record_health_check(&self, healthy: bool, latency: Duration) {
+        let mut health = self.health.lock().unwrap();
+
+        health.unhealthy_since = (!healthy).then(Instant::now);
+        // the latency of unhealthy endpoints is not representative, keep the last known latency
+        if healthy {
+            health.latency = Some(match health.latency {
+                Some(average_latency) => {
+                    average_latency.mul_f64(1.0 - LATENCY_EWMA_WEIGHT)
+                        + latency.mul_f64(LATENCY_EWMA_WEIGHT)
+                }
+                None => latency,
+            });
+        }
+    }
+}
+
+impl EndpointHealth {
+    fn is_healthy(&self, recovery_period: Duration) -> bool {
+        self.unhealthy_since.map_or(true, |unhealthy_since| {
+            unhealthy_since.elapsed() >= recovery_period
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// The CBOR encoding of `{"ic_api_version": "0.18.0", "replica_health_status": <status>}`.
+    fn status_body(replica_health_status: &str) -> Vec<u8> {
+        let mut body = vec![0xa2];
+        for value in [
+            "ic_api_version",
+            "0.18.0",
+            "replica_health_status",
+            replica_health_status,
+        ] {
+            body.push(0x60 + value.len() as u8);
+            body.extend_from_slice(value.as_bytes());
+        }
+
+        body
+    }
+
+    /// Starts a local stand-in for an API boundary node that answers every request
+    /// with the given replica health status after the given delay.
+    async fn start_stand_in(replica_health_status: &'static str, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    tokio::time::sleep(delay).await;
+
+                    let body = status_body(replica_health_status);
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/cbor\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let _ = socket.write_all(&body).await;
+                });
+            }
+        });
+
+        url
+    }
+
+    /// Returns the URL of a local port that refuses connections.
+    async fn unreachable_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_select_prefers_healthy_endpoints() {
+        let unreachable = unreachable_url().await;
+        let degraded = start_stand_in("degraded", Duration::ZERO).await;
+        let healthy = start_stand_in("healthy", Duration::ZERO).await;
+        let pool = EndpointPool::new([unreachable, degraded, healthy.clone()]).unwrap();
+
+        pool.check_health().await;
+
+        assert_eq!(pool.url(pool.select(&[]).unwrap()), Some(healthy.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_select_prefers_lower_latency() {
+        let slow = start_stand_in("healthy", Duration::from_millis(200)).await;
+        let fast = start_stand_in("healthy", Duration::ZERO).await;
+        let pool = EndpointPool::new([slow.clone(), fast.clone()]).unwrap();
+
+        pool.check_health().await;
+
+        assert_eq!(pool.select(&[]), Some(1));
+        assert_eq!(pool.select(&[1]), Some(0));
+        assert_eq!(pool.select(&[0, 1]), None);
+    }
+
+    #[tokio::test]
+    async fn test_select_falls_back_to_unhealthy_endpoints() {
+        let pool = EndpointPool::new([unreachable_url().await, unreachable_url().await])
+            .unwrap()
+            .with_health_check_timeout(Duration::from_secs(1));
+
+        pool.check_health().await;
+
+        assert_eq!(pool.select(&[]), Some(0));
+        assert_eq!(pool.select(&[0]), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_run_health_checks() {
+        let unreachable = unreachable_url().await;
+        let healthy = start_stand_in("healthy", Duration::ZERO).await;
+        let pool = EndpointPool::new([unreachable, healthy.clone()])
+            .unwrap()
+            .with_health_check_interval(Duration::from_millis(10));
+
+        let health_checks = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run_health_checks().await }
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while pool.select(&[]) != Some(1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        health_checks.abort();
+
+        assert_eq!(pool.url(pool.select(&[]).unwrap()), Some(healthy.as_str()));
+    }
+
+    // selecting an endpoint does not require a runtime
+    #[test]
+    fn test_mark_unhealthy() {
+        let pool = EndpointPool::new(["http://127.0.0.1:1", "http://127.0.0.1:2"]).unwrap();
+
+        assert_eq!(pool.select(&[]), Some(0));
+
+        pool.mark_unhealthy(0);
+
+        assert_eq!(pool.select(&[]), Some(1));
+    }
+
+    #[test]
+    fn test_unhealthy_endpoints_recover_without_health_checks() {
+        let pool = EndpointPool::new(["http://127.0.0.1:1", "http://127.0.0.1:2"])
+            .unwrap()
+            .with_health_check_interval(Duration::from_millis(20))
+            .with_health_check_timeout(Duration::from_millis(20));
+
+        pool.mark_unhealthy(0);
+        assert_eq!(pool.select(&[]), Some(1));
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.select(&[]), Some(0));
+    }
+
+    #[test]
+    fn test_set_root_key_requires_mainnet_endpoints() {
+        let mut pool = EndpointPool::new(["https://icp-api.io", "http://127.0.0.1:4943"]).unwrap();
+
+        assert!(matches!(
+            pool.set_root_key(None),
+            Err(HttpGatewayError::RootKeyRequired { endpoint }) if endpoint == "http://127.0.0.1:4943"
+        ));
+        assert!(matches!(
+            pool.set_root_key(Some(RootKey::Mainnet)),
+            Err(HttpGatewayError::RootKeyRequired { endpoint }) if endpoint == "http://127.0.0.1:4943"
+        ));
+        assert!(pool
+            .set_root_key(Some(RootKey::from_bytes(&[7; 96]).unwrap()))
+            .is_ok());
+
+        let mut pool = EndpointPool::new(["https://icp-api.io", "https://icp0.io"]).unwrap();
+        assert!(pool.set_root_key(None).is_ok());
+        assert!(pool.set_root_key(Some(RootKey::Mainnet)).is_ok());
+    }
+
+    #[test]
+    fn test_set_root_key_rejects_fetching_from_mainnet() {
+        let mut pool = EndpointPool::new(["http://127.0.0.1:4943", "https://icp0.io"]).unwrap();
+        assert!(matches!(
+            pool.set_root_key(Some(RootKey::InsecureFetchFromDevNetwork)),
+            Err(HttpGatewayError::InsecureRootKeyFetch { endpoint }) if endpoint == "https://icp0.io"
+        ));
+
+        let mut pool = EndpointPool::new(["http://127.0.0.1:4943"]).unwrap();
+        assert!(pool
+            .set_root_key(Some(RootKey::InsecureFetchFromDevNetwork))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_set_root_key_requires_configured_key_for_agents() {
+        let agent = Agent::builder()
+            .with_url("http://127.0.0.1:4943")
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            EndpointPool::from_agent(agent.clone()).set_root_key(None),
+            Err(HttpGatewayError::AgentRootKeyRequired)
+        ));
+        assert!(EndpointPool::from_agent(agent.clone())
+            .set_root_key(Some(RootKey::Mainnet))
+            .is_ok());
+        assert!(EndpointPool::from_agent(agent)
+            .set_root_key(Some(RootKey::from_bytes(&[7; 96]).unwrap()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_set_root_key_rejects_fetching_through_agents() {
+        let mut pool = EndpointPool::from_agent(
+            Agent::builder()
+                .with_url("http://127.0.0.1:4943")
+                .build()
+                .unwrap(),
+        );
+
+        assert!(matches!(
+            pool.set_root_key(Some(RootKey::InsecureFetchFromDevNetwork)),
+            Err(HttpGatewayError::AgentRootKeyFetch)
+        ));
+        assert!(!pool.fetch_root_key);
+    }
+
+    #[test]
+    fn test_new_rejects_empty_pool() {
+        assert!(matches!(
+            EndpointPool::new(Vec::<String>::new()),
+            Err(HttpGatewayError::EmptyEndpointPool)
+        ));
+    }
+
+    #[test]
+    fn test_record_health_check_averages_latency() {
+        let endpoint = Endpoint::new(
+            None,
+            Agent::builder()
+                .with_url("http://127.0.0.1:1")
+                .build()
+                .unwrap(),
+        );
+
+        endpoint.record_health_check(true, Duration::from_millis(100));
+        endpoint.record_health_check(true, Duration::from_millis(200));
+        endpoint.record_health_check(false, Duration::from_secs(5));
+
+        let health = *endpoint.health.lock().unwrap();
+        let latency = health.latency.unwrap();
+        assert!(!health.is_healthy(Duration::from_secs(60)));
+        assert!(
+            latency > Duration::from_millis(129) && latency < Duration::from_millis(131),
+            "unexpected latency {:?}",
+            latency
+        );
+    }
+}
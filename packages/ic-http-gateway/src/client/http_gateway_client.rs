@@ -1,22 +1,70 @@
 use crate::{
-    HttpGatewayClientBuilder, HttpGatewayRequestArgs, HttpGatewayRequestBuilder,
-    HttpGatewayRequestBuilderArgs,
+    AsyncUpdateCalls, DirectUpdateCalls, EndpointPool, ForwardingHeaders, HttpGatewayClientBuilder,
+    HttpGatewayRequestArgs, HttpGatewayRequestBuilder, HttpGatewayRequestBuilderArgs,
+    InvalidHeaderValueAction, RequestHeaderPolicy, ResponseHeaderSanitizer,
+    StreamingCallbackPolicy, StreamingLimits, StreamingPrefetch, SubnetRouting, UpgradePolicy,
+    UrlNormalization,
 };
-use ic_agent::Agent;
+use candid::Principal;
+use std::collections::HashMap;
 
 #[derive(Clone)]
 pub struct HttpGatewayClientArgs {
-    pub agent: Agent,
+    pub endpoint_pool: EndpointPool,
+    pub subnet_routing: Option<SubnetRouting>,
+    pub response_header_sanitizer: ResponseHeaderSanitizer,
+    pub forwarding_headers: Option<ForwardingHeaders>,
+    pub request_header_policy: RequestHeaderPolicy,
+    pub url_normalization: UrlNormalization,
+    pub invalid_header_value_action: InvalidHeaderValueAction,
+    pub async_update_calls: Option<AsyncUpdateCalls>,
+    pub upgrade_policy: UpgradePolicy,
+    pub direct_update_calls: Option<DirectUpdateCalls>,
+    pub streaming_prefetch: StreamingPrefetch,
+    pub streaming_limits: StreamingLimits,
+    pub canister_streaming_limits: HashMap<Principal, StreamingLimits>,
+    pub streaming_callback_policy: StreamingCallbackPolicy,
+    pub verify_query_signatures: bool,
 }
 
 #[derive(Clone)]
 pub struct HttpGatewayClient {
-    agent: Agent,
+    endpoint_pool: EndpointPool,
+    subnet_routing: Option<SubnetRouting>,
+    response_header_sanitizer: ResponseHeaderSanitizer,
+    forwarding_headers: Option<ForwardingHeaders>,
+    request_header_policy: RequestHeaderPolicy,
+    url_normalization: UrlNormalization,
+    invalid_header_value_action: InvalidHeaderValueAction,
+    async_update_calls: Option<AsyncUpdateCalls>,
+    upgrade_policy: UpgradePolicy,
+    direct_update_calls: Option<DirectUpdateCalls>,
+    streaming_prefetch: StreamingPrefetch,
+    streaming_limits: StreamingLimits,
+    canister_streaming_limits: HashMap<Principal, StreamingLimits>,
+    streaming_callback_policy: StreamingCallbackPolicy,
+    verify_query_signatures: bool,
 }
 
 impl HttpGatewayClient {
     pub fn new(args: HttpGatewayClientArgs) -> Self {
-        Self { agent: args.agent }
+        Self {
+            endpoint_pool: args.endpoint_pool,
+            subnet_routing: args.subnet_routing,
+            response_header_sanitizer: args.response_header_sanitizer,
+            forwarding_headers: args.forwarding_headers,
+            request_header_policy: args.request_header_policy,
+            url_normalization: args.url_normalization,
+            invalid_header_value_action: args.invalid_header_value_action,
+            async_update_calls: args.async_update_calls,
+            upgrade_policy: args.upgrade_policy,
+            direct_update_calls: args.direct_update_calls,
+            streaming_prefetch: args.streaming_prefetch,
+            streaming_limits: args.streaming_limits,
+            canister_streaming_limits: args.canister_streaming_limits,
+            streaming_callback_policy: args.streaming_callback_policy,
+            verify_query_signatures: args.verify_query_signatures,
+        }
     }
 
     pub fn builder() -> HttpGatewayClientBuilder {
@@ -24,9 +72,28 @@ impl HttpGatewayClient {
     }
 
     pub fn request(&self, args: HttpGatewayRequestArgs) -> HttpGatewayRequestBuilder {
+        let streaming_limits = self
+            .canister_streaming_limits
+            .get(&args.canister_id)
+            .copied()
+            .unwrap_or(self.streaming_limits);
+
         HttpGatewayRequestBuilder::new(HttpGatewayRequestBuilderArgs {
             request_args: args,
-            agent: &self.agent,
+            endpoint_pool: &self.endpoint_pool,
+            subnet_routing: self.subnet_routing.as_ref(),
+            response_header_sanitizer: &self.response_header_sanitizer,
+            forwarding_headers: self.forwarding_headers.as_ref(),
+            request_header_policy: &self.request_header_policy,
+            url_normalization: self.url_normalization,
+            invalid_header_value_action: self.invalid_header_value_action,
+            async_update_calls: self.async_update_calls.as_ref(),
+            upgrade_policy: &self.upgrade_policy,
+            direct_update_calls: self.direct_update_calls.as_ref(),
+            streaming_prefetch: self.streaming_prefetch,
+            streaming_limits,
+            streaming_callback_policy: &self.streaming_callback_policy,
+            verify_query_signatures: self.verify_query_signatures,
         })
     }
 }
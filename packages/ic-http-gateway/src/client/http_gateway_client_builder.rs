@@ -1,32 +1,227 @@
 use crate::{
-    HttpGatewayClient, HttpGatewayClientArgs, HttpGatewayResult, DEFAULT_BOUNDARY_NODE_ENDPOINT,
+    AsyncUpdateCalls, DirectUpdateCalls, EndpointPool, ForwardingHeaders, HttpGatewayClient,
+    HttpGatewayClientArgs, HttpGatewayResult, InvalidHeaderValueAction, RequestHeaderPolicy,
+    ResponseHeaderSanitizer, RootKey, StreamingCallbackPolicy, StreamingLimits, StreamingPrefetch,
+    SubnetRouting, UpgradePolicy, UrlNormalization, DEFAULT_BOUNDARY_NODE_ENDPOINT,
 };
+use candid::Principal;
 use ic_agent::Agent;
+use std::collections::HashMap;
 
 pub struct HttpGatewayClientBuilder {
-    agent: Option<Agent>,
+    endpoint_pool: Option<EndpointPool>,
+    root_key: Option<RootKey>,
+    subnet_routing: Option<SubnetRouting>,
+    response_header_sanitizer: ResponseHeaderSanitizer,
+    forwarding_headers: Option<ForwardingHeaders>,
+    request_header_policy: RequestHeaderPolicy,
+    url_normalization: UrlNormalization,
+    invalid_header_value_action: InvalidHeaderValueAction,
+    async_update_calls: Option<AsyncUpdateCalls>,
+    upgrade_policy: UpgradePolicy,
+    direct_update_calls: Option<DirectUpdateCalls>,
+    streaming_prefetch: StreamingPrefetch,
+    streaming_limits: StreamingLimits,
+    canister_streaming_limits: HashMap<Principal, StreamingLimits>,
+    streaming_callback_policy: StreamingCallbackPolicy,
+    verify_query_signatures: bool,
 }
 
 impl HttpGatewayClientBuilder {
     pub fn new() -> Self {
-        Self { agent: None }
+        Self {
+            endpoint_pool: None,
+            root_key: None,
+            subnet_routing: None,
+            response_header_sanitizer: ResponseHeaderSanitizer::default(),
+            forwarding_headers: None,
+            request_header_policy: RequestHeaderPolicy::default(),
+            url_normalization: UrlNormalization::default(),
+            invalid_header_value_action: InvalidHeaderValueAction::default(),
+            async_update_calls: None,
+            upgrade_policy: UpgradePolicy::default(),
+            direct_update_calls: None,
+            streaming_prefetch: StreamingPrefetch::default(),
+            streaming_limits: StreamingLimits::default(),
+            canister_streaming_limits: HashMap::new(),
+            streaming_callback_policy: StreamingCallbackPolicy::default(),
+            verify_query_signatures: false,
+        }
     }
 
+    /// Routes all requests through the given agent. Its endpoint is unknown to the client,
+    /// so a root key must be configured with [with_root_key](HttpGatewayClientBuilder::with_root_key).
     pub fn with_agent(mut self, agent: Agent) -> Self {
-        self.agent = Some(agent);
+        self.endpoint_pool = Some(EndpointPool::from_agent(agent));
+
+        self
+    }
+
+    /// Routes requests to the endpoints of the given pool, replacing the agent set with
+    /// [with_agent](HttpGatewayClientBuilder::with_agent).
+    pub fn with_endpoint_pool(mut self, endpoint_pool: EndpointPool) -> Self {
+        self.endpoint_pool = Some(endpoint_pool);
+
+        self
+    }
+
+    /// Sets the root key that certificates are verified against, the mainnet root key by default.
+    /// Building the client fails if requests would be routed to an endpoint that is not part of mainnet
+    /// without configuring a root key. The endpoint of an agent set with
+    /// [with_agent](HttpGatewayClientBuilder::with_agent) is unknown, so a root key must always be
+    /// configured to use it, and it cannot be fetched through the agent.
+    /// Fetching the root key from a mainnet endpoint is rejected.
+    pub fn with_root_key(mut self, root_key: RootKey) -> Self {
+        self.root_key = Some(root_key);
+
+        self
+    }
+
+    /// Enables sending query calls directly to the nodes of the subnet that hosts the requested canister.
+    pub fn with_subnet_routing(mut self, subnet_routing: SubnetRouting) -> Self {
+        self.subnet_routing = Some(subnet_routing);
+
+        self
+    }
+
+    /// Sets the sanitizer that is applied to the headers of responses that are not fully certified.
+    pub fn with_response_header_sanitizer(
+        mut self,
+        response_header_sanitizer: ResponseHeaderSanitizer,
+    ) -> Self {
+        self.response_header_sanitizer = response_header_sanitizer;
+
+        self
+    }
+
+    /// Enables the injection of forwarding headers into requests made to canisters,
+    /// computed from the [ClientInfo](crate::ClientInfo) passed with each request.
+    pub fn with_forwarding_headers(mut self, forwarding_headers: ForwardingHeaders) -> Self {
+        self.forwarding_headers = Some(forwarding_headers);
+
+        self
+    }
+
+    /// Sets the policy that controls which request headers are forwarded to canisters.
+    pub fn with_request_header_policy(
+        mut self,
+        request_header_policy: RequestHeaderPolicy,
+    ) -> Self {
+        self.request_header_policy = request_header_policy;
+
+        self
+    }
+
+    /// Sets how request URLs are normalized before they are sent to canisters.
+    pub fn with_url_normalization(mut self, url_normalization: UrlNormalization) -> Self {
+        self.url_normalization = url_normalization;
+
+        self
+    }
+
+    /// Sets what to do with request and response header values that cannot be represented as-is.
+    pub fn with_invalid_header_value_action(
+        mut self,
+        invalid_header_value_action: InvalidHeaderValueAction,
+    ) -> Self {
+        self.invalid_header_value_action = invalid_header_value_action;
+
+        self
+    }
+
+    /// Enables the asynchronous update call mode, where requests that are upgraded to update calls
+    /// are answered with `202 Accepted` and a status URL that can be polled for the result.
+    pub fn with_async_update_calls(mut self, async_update_calls: AsyncUpdateCalls) -> Self {
+        self.async_update_calls = Some(async_update_calls);
+
+        self
+    }
+
+    /// Sets the policy that controls which requests canisters may upgrade to update calls.
+    pub fn with_upgrade_policy(mut self, upgrade_policy: UpgradePolicy) -> Self {
+        self.upgrade_policy = upgrade_policy;
+
+        self
+    }
+
+    /// Enables direct update calls, which skip the query call for requests
+    /// that are known to be upgraded to update calls.
+    pub fn with_direct_update_calls(mut self, direct_update_calls: DirectUpdateCalls) -> Self {
+        self.direct_update_calls = Some(direct_update_calls);
+
+        self
+    }
+
+    /// Sets how many streaming callback chunks are fetched ahead of time.
+    pub fn with_streaming_prefetch(mut self, streaming_prefetch: StreamingPrefetch) -> Self {
+        self.streaming_prefetch = streaming_prefetch;
+
+        self
+    }
+
+    /// Sets the limits that apply to responses using the streaming callback strategy.
+    pub fn with_streaming_limits(mut self, streaming_limits: StreamingLimits) -> Self {
+        self.streaming_limits = streaming_limits;
+
+        self
+    }
+
+    /// Overrides the streaming limits for responses of the given canister.
+    pub fn with_canister_streaming_limits(
+        mut self,
+        canister_id: Principal,
+        streaming_limits: StreamingLimits,
+    ) -> Self {
+        self.canister_streaming_limits
+            .insert(canister_id, streaming_limits);
+
+        self
+    }
+
+    /// Sets the policy that controls which canisters may be called by streaming callbacks.
+    pub fn with_streaming_callback_policy(
+        mut self,
+        streaming_callback_policy: StreamingCallbackPolicy,
+    ) -> Self {
+        self.streaming_callback_policy = streaming_callback_policy;
+
+        self
+    }
+
+    /// Verifies the signatures of replica nodes on the responses to query calls,
+    /// including the calls to streaming callbacks, even if the agents are configured
+    /// not to verify them. Responses with missing or invalid signatures are rejected.
+    /// Disabled by default, in which case the agents' own configuration applies.
+    pub fn with_verify_query_signatures(mut self, verify_query_signatures: bool) -> Self {
+        self.verify_query_signatures = verify_query_signatures;
 
         self
     }
 
     pub fn build(self) -> HttpGatewayResult<HttpGatewayClient> {
-        let agent = match self.agent {
-            Some(agent) => agent,
-            None => Agent::builder()
-                .with_url(DEFAULT_BOUNDARY_NODE_ENDPOINT)
-                .build()?,
+        let mut endpoint_pool = match self.endpoint_pool {
+            Some(endpoint_pool) => endpoint_pool,
+            None => EndpointPool::new([DEFAULT_BOUNDARY_NODE_ENDPOINT])?,
         };
+        endpoint_pool.set_root_key(self.root_key)?;
 
-        Ok(HttpGatewayClient::new(HttpGatewayClientArgs { agent }))
+        Ok(HttpGatewayClient::new(HttpGatewayClientArgs {
+            endpoint_pool,
+            subnet_routing: self.subnet_routing,
+            response_header_sanitizer: self.response_header_sanitizer,
+            forwarding_headers: self.forwarding_headers,
+            request_header_policy: self.request_header_policy,
+            url_normalization: self.url_normalization,
+            invalid_header_value_action: self.invalid_header_value_action,
+            async_update_calls: self.async_update_calls,
+            upgrade_policy: self.upgrade_policy,
+            direct_update_calls: self.direct_update_calls,
+            streaming_prefetch: self.streaming_prefetch,
+            streaming_limits: self.streaming_limits,
+            canister_streaming_limits: self.canister_streaming_limits,
+            streaming_callback_policy: self.streaming_callback_policy,
+            verify_query_signatures: self.verify_query_signatures,
+        }))
     }
 }
 
@@ -3,3 +3,12 @@ pub use http_gateway_client::*;
 
 mod http_gateway_client_builder;
 pub use http_gateway_client_builder::*;
+
+mod endpoint_pool;
+pub use endpoint_pool::*;
+
+mod root_key;
+pub use root_key::*;
+
+mod subnet_routing;
+pub use subnet_routing::*;
@@ -0,0 +1,169 @@
+use crate::{HttpGatewayError, HttpGatewayResult, MAINNET_DOMAINS};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http::Uri;
+use std::path::Path;
+
+/// The DER prefix of a BLS12-381 public key, as used for the root key of the Internet Computer.
+const DER_PREFIX: &[u8; 37] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00";
+
+const KEY_LENGTH: usize = 96;
+
+const PEM_BEGIN: &str = "-----BEGIN PUBLIC KEY-----";
+
+const PEM_END: &str = "-----END PUBLIC KEY-----";
+
+/// The root key that certificates returned by the Internet Computer are verified against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RootKey {
+    /// The root key of the Internet Computer mainnet, which is built into the agent.
+    /// Requests can only be routed to mainnet endpoints with this root key.
+    #[default]
+    Mainnet,
+
+    /// A DER-encoded root key, pinned ahead of time.
+    Pinned(Vec<u8>),
+
+    /// The root key is fetched once from each endpoint before the first request is routed to it.
+    ///
+    /// This is insecure, because the gateway trusts whatever key the endpoint returns,
+    /// and must only be used with local development networks such as dfx or PocketIC.
+    InsecureFetchFromDevNetwork,
+}
+
+impl RootKey {
+    /// Pins a raw, 96 byte BLS12-381 public key.
+    pub fn from_bytes(key: &[u8]) -> HttpGatewayResult<Self> {
+        if key.len() != KEY_LENGTH {
+            return Err(HttpGatewayError::InvalidRootKey(format!(
+                "expected {} bytes, got {}",
+                KEY_LENGTH,
+                key.len()
+            )));
+        }
+
+        Ok(RootKey::Pinned([DER_PREFIX.as_slice(), key].concat()))
+    }
+
+    /// Pins a DER-encoded BLS12-381 public key.
+    pub fn from_der(der: &[u8]) -> HttpGatewayResult<Self> {
+        match der.strip_prefix(DER_PREFIX.as_slice()) {
+            Some(key) => Self::from_bytes(key),
+            None => Err(HttpGatewayError::InvalidRootKey(
+                "not a DER-encoded BLS12-381 public key".to_string(),
+            )),
+        }
+    }
+
+    /// Pins a PEM-encoded BLS12-381 public key.
+    pub fn from_pem(pem: &str) -> HttpGatewayResult<Self> {
+        let base64 = pem
+            .trim()
+            .strip_prefix(PEM_BEGIN)
+            .and_then(|pem| pem.strip_suffix(PEM_END))
+            .ok_or_else(|| {
+                HttpGatewayError::InvalidRootKey(format!(
+                    r#"expected a PEM block starting with "{}""#,
+                    PEM_BEGIN
+                ))
+            })?
+            .split_whitespace()
+            .collect::<String>();
+
+        let der = BASE64
+            .decode(base64)
+            .map_err(|e| HttpGatewayError::InvalidRootKey(e.to_string()))?;
+
+        Self::from_der(&der)
+    }
+
+    /// Pins the PEM-encoded BLS12-381 public key stored in the file at `path`.
+    pub fn from_pem_file(path: impl AsRef<Path>) -> HttpGatewayResult<Self> {
+        let path = path.as_ref();
+        let pem = std::fs::read_to_string(path).map_err(|e| {
+            HttpGatewayError::InvalidRootKey(format!(
+                r#"failed to read "{}": {}"#,
+                path.display(),
+                e
+            ))
+        })?;
+
+        Self::from_pem(&pem)
+    }
+}
+
+/// Returns whether `url` points to an endpoint of the Internet Computer mainnet.
+pub(crate) fn is_mainnet_url(url: &str) -> bool {
+    let Some(host) = url.parse::<Uri>().ok().and_then(|uri| {
+        uri.host()
+            .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+    }) else {
+        return false;
+    };
+
+    MAINNET_DOMAINS.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LENGTH] = [7; KEY_LENGTH];
+
+    #[test]
+    fn test_from_bytes() {
+        let RootKey::Pinned(der) = RootKey::from_bytes(&KEY).unwrap() else {
+            panic!("expected a pinned root key");
+        };
+
+        assert_eq!(der.len(), 133);
+        assert_eq!(RootKey::from_der(&der).unwrap(), RootKey::Pinned(der));
+        assert!(RootKey::from_bytes(&KEY[1..]).is_err());
+    }
+
+    #[test]
+    fn test_from_der_rejects_other_keys() {
+        assert!(RootKey::from_der(&KEY).is_err());
+        assert!(RootKey::from_der(&[DER_PREFIX.as_slice(), &KEY[1..]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_from_pem() {
+        let der = [DER_PREFIX.as_slice(), &KEY].concat();
+        let encoded = BASE64.encode(&der);
+        let pem = format!(
+            "{}\n{}\n{}\n{}\n",
+            PEM_BEGIN,
+            &encoded[..64],
+            &encoded[64..],
+            PEM_END
+        );
+
+        assert_eq!(RootKey::from_pem(&pem).unwrap(), RootKey::Pinned(der));
+        assert!(RootKey::from_pem(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_from_pem_file_missing() {
+        assert!(matches!(
+            RootKey::from_pem_file("/nonexistent/root_key.pem"),
+            Err(HttpGatewayError::InvalidRootKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_mainnet_url() {
+        assert!(is_mainnet_url("https://icp-api.io"));
+        assert!(is_mainnet_url("https://ICP0.io."));
+        assert!(is_mainnet_url("https://boundary.ic0.app/"));
+
+        assert!(!is_mainnet_url("http://127.0.0.1:4943"));
+        assert!(!is_mainnet_url("https://evilicp-api.io"));
+        assert!(!is_mainnet_url("https://icp-api.io.example.com"));
+        assert!(!is_mainnet_url("not a url"));
+    }
+}
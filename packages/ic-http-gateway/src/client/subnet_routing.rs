@@ -0,0 +1,416 @@
+use crate::HttpGatewayResult;
+use candid::Principal;
+use futures::future::BoxFuture;
+use ic_agent::Agent;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+const DEFAULT_ROUTING_TABLE_TTL: Duration = Duration::from_secs(300);
+
+const DEFAULT_FETCH_FAILURE_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A subnet, the ranges of canister ids that are hosted on it and the endpoints of its nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubnetRoute {
+    /// The id of the subnet.
+    pub subnet_id: Principal,
+
+    /// The inclusive ranges of canister ids that are hosted on the subnet.
+    pub canister_ranges: Vec<(Principal, Principal)>,
+
+    /// The URLs of the subnet's node endpoints.
+    pub endpoints: Vec<String>,
+}
+
+impl SubnetRoute {
+    fn contains(&self, canister_id: &Principal) -> bool {
+        self.canister_ranges.iter().any(|(start, end)| {
+            start.as_slice() <= canister_id.as_slice() && canister_id.as_slice() <= end.as_slice()
+        })
+    }
+}
+
+/// Provides the subnet routing table, which maps canister ids to the endpoints of their subnet's nodes.
+///
+/// Implementations may, for example, read the canister ranges of subnets from the certified state
+/// using `read_state` and the node endpoints from the NNS registry.
+pub trait SubnetRoutingProvider: Send + Sync {
+    /// Fetches the current subnet routing table.
+    fn fetch_routes(&self) -> BoxFuture<'_, HttpGatewayResult<Vec<SubnetRoute>>>;
+}
+
+/// A [SubnetRoutingProvider] that always provides the same routing table.
+#[derive(Debug, Clone, Default)]
+pub struct StaticSubnetRoutingProvider {
+    routes: Vec<SubnetRoute>,
+}
+
+impl StaticSubnetRoutingProvider {
+    pub fn new(routes: Vec<SubnetRoute>) -> Self {
+        Self { routes }
+    }
+}
+
+impl SubnetRoutingProvider for StaticSubnetRoutingProvider {
+    fn fetch_routes(&self) -> BoxFuture<'_, HttpGatewayResult<Vec<SubnetRoute>>> {
+        Box::pin(async move { Ok(self.routes.clone()) })
+    }
+}
+
+/// Sends query calls directly to the nodes of the subnet that hosts the requested canister,
+/// instead of the endpoint that the request is routed to. Update calls are not affected.
+///
+/// The routing table is fetched from the provider when it is first needed, cached for the
+/// routing table TTL and refreshed early when a node rejects a query with `DestinationInvalid`.
+/// If the routing table cannot be fetched, it is fetched again after the fetch failure backoff.
+/// Requests to canisters that are not part of the routing table are sent to the endpoint as usual.
+#[derive(Clone)]
+pub struct SubnetRouting {
+    provider: Arc<dyn SubnetRoutingProvider>,
+    routing_table_ttl: Duration,
+    fetch_failure_backoff: Duration,
+    routing_table: Arc<Mutex<Option<RoutingTable>>>,
+}
+
+struct RoutingTable {
+    expires_at: Instant,
+    routes: Arc<[ResolvedSubnetRoute]>,
+    agents: HashMap<String, Agent>,
+}
+
+struct ResolvedSubnetRoute {
+    route: SubnetRoute,
+    agents: Vec<(String, Agent)>,
+    next_agent: AtomicUsize,
+}
+
+/// The node endpoint that query calls for a request are sent to.
+#[derive(Clone)]
+pub(crate) struct SubnetEndpoint {
+    pub url: String,
+    pub agent: Agent,
+}
+
+impl SubnetRouting {
+    pub fn new(provider: impl SubnetRoutingProvider + 'static) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            routing_table_ttl: DEFAULT_ROUTING_TABLE_TTL,
+            fetch_failure_backoff: DEFAULT_FETCH_FAILURE_BACKOFF,
+            routing_table: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets how long the routing table is cached before it is fetched again.
+    pub fn with_routing_table_ttl(mut self, routing_table_ttl: Duration) -> Self {
+        self.routing_table_ttl = routing_table_ttl;
+
+        self
+    }
+
+    /// Sets how long to wait before fetching the routing table again after fetching it failed.
+    pub fn with_fetch_failure_backoff(mut self, fetch_failure_backoff: Duration) -> Self {
+        self.fetch_failure_backoff = fetch_failure_backoff;
+
+        self
+    }
+
+    /// Returns the node endpoint to send query calls for `canister_id` to, if the canister is part of
+    /// the routing table. The agents of node endpoints are created when the routing table is fetched
+    /// and verify certificates against the root key of `endpoint_agent` at that time,
+    /// the agent of the endpoint that the request is routed to.
+    pub(crate) async fn subnet_endpoint(
+        &self,
+        canister_id: &Principal,
+        endpoint_agent: &Agent,
+    ) -> Option<SubnetEndpoint> {
+        let routes = self.routes(endpoint_agent).await;
+
+        let route = routes
+            .iter()
+            .find(|route| route.route.contains(canister_id))?;
+        if route.agents.is_empty() {
+            return None;
+        }
+
+        let next_agent = route.next_agent.fetch_add(1, Ordering::Relaxed);
+        let (url, agent) = &route.agents[next_agent % route.agents.len()];
+
+        Some(SubnetEndpoint {
+            url: url.clone(),
+            agent: agent.clone(),
+        })
+    }
+
+    /// Expires the cached routing table, so that it is fetched again for the next request.
+    pub(crate) async fn invalidate(&self) {
+        if let Some(routing_table) = self.routing_table.lock().await.as_mut() {
+            routing_table.expires_at = Instant::now();
+        }
+    }
+
+    async fn routes(&self, endpoint_agent: &Agent) -> Arc<[ResolvedSubnetRoute]> {
+        let mut routing_table = self.routing_table.lock().await;
+
+        if let Some(routing_table) = routing_table
+            .as_ref()
+            .filter(|routing_table| routing_table.expires_at > Instant::now())
+        {
+            return Arc::clone(&routing_table.routes);
+        }
+
+        let root_key = endpoint_agent.read_root_key();
+        let mut agents = routing_table
+            .take()
+            .map(|routing_table| routing_table.agents)
+            .unwrap_or_default();
+
+        let (routes, expires_at) = match self.provider.fetch_routes().await {
+            Ok(routes) => {
+                let routes: Arc<[_]> = routes
+                    .into_iter()
+                    .map(|route| resolve_route(route, &root_key, &mut agents))
+                    .collect();
+                agents.retain(|url, _| {
+                    routes
+                        .iter()
+                        .any(|route| route.route.endpoints.contains(url))
+                });
+
+                (routes, Instant::now() + self.routing_table_ttl)
+            }
+            // keep routing queries to the endpoints until the routing table can be fetched again,
+            // rather than trying to fetch it for every request
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to fetch the subnet routing table, retrying in {:?}: {}",
+                    self.fetch_failure_backoff,
+                    e
+                );
+
+                (
+                    Arc::from([]),
+                    Instant::now() + self.fetch_failure_backoff.min(self.routing_table_ttl),
+                )
+            }
+        };
+
+        *routing_table = Some(RoutingTable {
+            expires_at,
+            routes: Arc::clone(&routes),
+            agents,
+        });
+
+        routes
+    }
+}
+
+/// Creates the agents for the node endpoints of `route` that verify certificates against `root_key`,
+/// reusing existing agents for known endpoints that use the same root key. The root key of an agent
+/// is only set when it is created, since the agents are shared between concurrent requests.
+fn resolve_route(
+    route: SubnetRoute,
+    root_key: &[u8],
+    agents: &mut HashMap<String, Agent>,
+) -> ResolvedSubnetRoute {
+    let route_agents = route
+        .endpoints
+        .iter()
+        .filter_map(|url| {
+            if let Some(agent) = agents
+                .get(url)
+                .filter(|agent| agent.read_root_key() == root_key)
+            {
+                return Some((url.clone(), agent.clone()));
+            }
+
+            match Agent::builder().with_url(url.as_str()).build() {
+                Ok(agent) => {
+                    agent.set_root_key(root_key.to_vec());
+                    agents.insert(url.clone(), agent.clone());
+
+                    Some((url.clone(), agent))
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to create an agent for node endpoint {} of subnet {}: {}",
+                        url,
+                        route.subnet_id,
+                        e
+                    );
+
+                    None
+                }
+            }
+        })
+        .collect();
+
+    ResolvedSubnetRoute {
+        route,
+        agents: route_agents,
+        next_agent: AtomicUsize::new(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpGatewayError;
+
+    /// Counts how often the routing table was fetched.
+    struct CountingProvider {
+        fetches: Arc<AtomicUsize>,
+        routes: Vec<SubnetRoute>,
+    }
+
+    impl SubnetRoutingProvider for CountingProvider {
+        fn fetch_routes(&self) -> BoxFuture<'_, HttpGatewayResult<Vec<SubnetRoute>>> {
+            Box::pin(async move {
+                self.fetches.fetch_add(1, Ordering::SeqCst);
+
+                Ok(self.routes.clone())
+            })
+        }
+    }
+
+    /// Counts how often fetching the routing table failed.
+    struct FailingProvider {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl SubnetRoutingProvider for FailingProvider {
+        fn fetch_routes(&self) -> BoxFuture<'_, HttpGatewayResult<Vec<SubnetRoute>>> {
+            Box::pin(async move {
+                self.fetches.fetch_add(1, Ordering::SeqCst);
+
+                Err(HttpGatewayError::HttpError("unavailable".to_string()))
+            })
+        }
+    }
+
+    fn test_canister_id(id: u8) -> Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, id, 1, 1])
+    }
+
+    fn route() -> SubnetRoute {
+        SubnetRoute {
+            subnet_id: Principal::from_slice(&[1; 29]),
+            canister_ranges: vec![(test_canister_id(0), test_canister_id(5))],
+            endpoints: vec![
+                "http://127.0.0.1:8081".to_string(),
+                "http://127.0.0.1:8082".to_string(),
+            ],
+        }
+    }
+
+    fn endpoint_agent() -> Agent {
+        Agent::builder()
+            .with_url("http://127.0.0.1:4943")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_subnet_route_contains() {
+        let route = route();
+
+        assert!(route.contains(&test_canister_id(0)));
+        assert!(route.contains(&test_canister_id(1)));
+        assert!(route.contains(&test_canister_id(5)));
+        assert!(!route.contains(&test_canister_id(6)));
+        assert!(!route.contains(&Principal::anonymous()));
+    }
+
+    #[tokio::test]
+    async fn test_subnet_endpoint_rotates_node_endpoints() {
+        let subnet_routing = SubnetRouting::new(StaticSubnetRoutingProvider::new(vec![route()]));
+        let endpoint_agent = endpoint_agent();
+        let canister_id = test_canister_id(1);
+
+        let first = subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await
+            .unwrap();
+        let second = subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await
+            .unwrap();
+
+        assert_eq!(first.url, "http://127.0.0.1:8081");
+        assert_eq!(second.url, "http://127.0.0.1:8082");
+        assert_eq!(first.agent.read_root_key(), endpoint_agent.read_root_key());
+        assert!(subnet_routing
+            .subnet_endpoint(&test_canister_id(8), &endpoint_agent)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_routing_table_is_cached_until_invalidated() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let subnet_routing = SubnetRouting::new(CountingProvider {
+            fetches: Arc::clone(&fetches),
+            routes: vec![route()],
+        });
+        let endpoint_agent = endpoint_agent();
+        let canister_id = test_canister_id(1);
+
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        subnet_routing.invalidate().await;
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_falls_back_to_endpoint() {
+        let subnet_routing = SubnetRouting::new(FailingProvider {
+            fetches: Arc::new(AtomicUsize::new(0)),
+        });
+
+        assert!(subnet_routing
+            .subnet_endpoint(&test_canister_id(1), &endpoint_agent())
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetch_is_retried_after_backoff() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let subnet_routing = SubnetRouting::new(FailingProvider {
+            fetches: Arc::clone(&fetches),
+        })
+        .with_fetch_failure_backoff(Duration::from_millis(20));
+        let endpoint_agent = endpoint_agent();
+        let canister_id = test_canister_id(1);
+
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        subnet_routing
+            .subnet_endpoint(&canister_id, &endpoint_agent)
+            .await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,4 +1,50 @@
 pub(crate) static CACHE_HEADER_NAME: &str = "cache-control";
 pub(crate) static ACCEPT_ENCODING_HEADER_NAME: &str = "accept-encoding";
+pub(crate) static HOST_HEADER_NAME: &str = "host";
+pub(crate) static CONNECTION_HEADER_NAME: &str = "connection";
+pub(crate) static FORWARDED_HEADER_NAME: &str = "forwarded";
+pub(crate) static X_FORWARDED_FOR_HEADER_NAME: &str = "x-forwarded-for";
+pub(crate) static X_FORWARDED_PROTO_HEADER_NAME: &str = "x-forwarded-proto";
+pub(crate) static X_REAL_IP_HEADER_NAME: &str = "x-real-ip";
+pub(crate) static TE_HEADER_NAME: &str = "te";
+pub(crate) static CONTENT_DIGEST_TRAILER_NAME: &str = "content-digest";
+pub(crate) static CHUNK_COUNT_TRAILER_NAME: &str = "x-ic-stream-chunk-count";
+pub(crate) static CERTIFIED_TRAILER_NAME: &str = "x-ic-stream-certified";
+pub(crate) static CERTIFICATE_EXPRESSION_HEADER_NAME: &str = "ic-certificateexpression";
+
+/// The field of the validation arguments of a certificate expression that skips certification.
+pub(crate) static SKIP_CERTIFICATION_FIELD_NAME: &str = "no_certification";
+
+/// Hop-by-hop headers as listed in RFC 9110, section 7.6.1, along with the
+/// legacy `keep-alive` and `proxy-connection` headers.
+pub(crate) static HOP_BY_HOP_HEADER_NAMES: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers that allow an uncertified response to affect more than its own body,
+/// such as setting cookies, redirecting the client or relaxing browser security policies.
+pub(crate) static DANGEROUS_RESPONSE_HEADER_NAMES: [&str; 10] = [
+    "set-cookie",
+    "set-cookie2",
+    "location",
+    "refresh",
+    "content-security-policy",
+    "content-security-policy-report-only",
+    "access-control-allow-origin",
+    "access-control-allow-credentials",
+    "clear-site-data",
+    "service-worker-allowed",
+];
 
 pub(crate) static DEFAULT_BOUNDARY_NODE_ENDPOINT: &str = "https://icp-api.io";
+
+/// Domains (and their subdomains) that serve the Internet Computer mainnet.
+pub(crate) static MAINNET_DOMAINS: [&str; 3] = ["icp-api.io", "icp0.io", "ic0.app"];
@@ -1,6 +1,7 @@
 //! The error module contains types for common errors that may be thrown
 //! by other modules in this crate.
 
+use candid::Principal;
 use std::sync::Arc;
 
 /// HTTP gateway result type.
@@ -25,6 +26,67 @@ pub enum HttpGatewayError {
         header_name: String,
         header_value: String,
     },
+
+    /// The request URL could not be normalized.
+    #[error(r#"Malformed request URL "{url}": {reason}"#)]
+    MalformedRequestUrl { url: String, reason: String },
+
+    /// The headers forwarded to the canister exceed the configured size limit.
+    #[error(r#"Request headers are too large: {total_header_bytes} bytes exceeds the limit of {max_total_header_bytes} bytes"#)]
+    RequestHeadersTooLarge {
+        total_header_bytes: usize,
+        max_total_header_bytes: usize,
+    },
+
+    /// The canister tried to upgrade a request to an update call, but the upgrade policy does not allow it.
+    #[error(r#"Upgrading {method} "{path}" to an update call is not allowed for canister {canister_id}"#)]
+    UpdateCallUpgradeNotAllowed {
+        canister_id: Principal,
+        method: String,
+        path: String,
+    },
+
+    /// The canister exceeded the rate limit of upgrades to update calls.
+    #[error("Too many upgrades to update calls for canister {canister_id}")]
+    UpdateCallUpgradeRateLimited { canister_id: Principal },
+
+    /// The streaming callback of the response targets a canister that is not allowed by the streaming callback policy.
+    #[error("The streaming callback of canister {canister_id} targets canister {callback_canister_id}, which is not allowed")]
+    StreamingCallbackCanisterNotAllowed {
+        canister_id: Principal,
+        callback_canister_id: Principal,
+    },
+
+    /// The response body could not be streamed from the canister.
+    #[error(transparent)]
+    ResponseBodyStreamError(#[from] crate::ResponseBodyStreamError),
+
+    /// An endpoint pool was created without any endpoints.
+    #[error("An endpoint pool must contain at least one endpoint")]
+    EmptyEndpointPool,
+
+    /// A root key could not be parsed or loaded.
+    #[error("Invalid root key: {0}")]
+    InvalidRootKey(String),
+
+    /// Requests would be routed to an endpoint that is not part of mainnet,
+    /// but no root key was configured to verify its certificates with.
+    #[error(r#"Endpoint "{endpoint}" is not a mainnet endpoint, a root key must be configured to use it"#)]
+    RootKeyRequired { endpoint: String },
+
+    /// Requests would be routed through an agent that was created outside of the client, whose endpoint
+    /// is unknown, but no root key was configured to verify its certificates with.
+    #[error("A root key must be configured to route requests through an agent")]
+    AgentRootKeyRequired,
+
+    /// The root key would be fetched from a mainnet endpoint instead of using the mainnet root key.
+    #[error(r#"Endpoint "{endpoint}" is a mainnet endpoint, its root key must not be fetched"#)]
+    InsecureRootKeyFetch { endpoint: String },
+
+    /// The root key would be fetched through an agent that was created outside of the client,
+    /// whose endpoint is unknown and may be part of mainnet.
+    #[error("The root key must not be fetched through an agent, configure its root key instead")]
+    AgentRootKeyFetch,
 }
 
 impl From<ic_agent::AgentError> for HttpGatewayError {
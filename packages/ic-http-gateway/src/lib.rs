@@ -18,3 +18,6 @@ pub(crate) use consts::*;
 
 mod error;
 pub use error::*;
+
+#[cfg(feature = "testing")]
+pub mod testing;
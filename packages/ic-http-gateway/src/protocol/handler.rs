@@ -1,24 +1,44 @@
-use super::validate;
+use super::{
+    call_and_wait_certified, decode_update_reply, http_request_query, validate, wait_certified,
+    HttpRequestArg,
+};
 use crate::{
-    get_body_and_streaming_body, CanisterRequest, CanisterResponse, HttpGatewayError,
-    HttpGatewayResponse, HttpGatewayResponseBody, HttpGatewayResponseMetadata, HttpGatewayResult,
-    ACCEPT_ENCODING_HEADER_NAME, CACHE_HEADER_NAME,
+    accepts_trailers, convert_request_header_value, convert_response_header_value,
+    get_body_and_streaming_body, integrity_trailer_names, normalize_url, AgentResponseAny,
+    AsyncUpdateCalls, CanisterRequest, CanisterResponse, HttpGatewayError, HttpGatewayRequestArgs,
+    HttpGatewayRequestBuilderArgs, HttpGatewayResponse, HttpGatewayResponseBody,
+    HttpGatewayResponseMetadata, HttpGatewayResult, InvalidHeaderValue, InvalidHeaderValueAction,
+    RequestHeaderPolicy, ResponseBodyStreamError, ResponseHeaderSanitizer, StreamingBodyOptions,
+    UrlNormalization, ACCEPT_ENCODING_HEADER_NAME, CACHE_HEADER_NAME, HOST_HEADER_NAME,
 };
+use bytes::Bytes;
 use candid::Principal;
-use http::{Response, StatusCode};
+use http::{header, Response, StatusCode};
 use http_body_util::{BodyExt, Either, Full};
 use ic_agent::{
-    agent::{RejectCode, RejectResponse},
+    agent::{RejectCode, RejectResponse, ReplyResponse, RequestStatusResponse},
     Agent, AgentError,
 };
 use ic_http_certification::{HttpRequest, HttpResponse};
-use ic_response_verification::MAX_VERIFICATION_VERSION;
+use ic_response_verification::{types::VerificationInfo, MAX_VERIFICATION_VERSION};
 use ic_utils::{
-    call::{AsyncCall, SyncCall},
+    call::AsyncCall,
     interfaces::{http_request::HeaderField, HttpRequestCanister},
 };
 
-fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
+pub(crate) fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
+    create_text_response(status_code, msg)
+}
+
+/// Creates a response that the gateway answers a request with on its own without it having failed,
+/// as opposed to the responses of [create_err_response].
+fn create_success_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
+    debug_assert!(status_code.is_success());
+
+    create_text_response(status_code, msg)
+}
+
+fn create_text_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
     let mut response = Response::new(HttpGatewayResponseBody::Right(Full::from(
         msg.as_bytes().to_vec(),
     )));
@@ -27,60 +47,145 @@ fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
     response
 }
 
-fn convert_request(request: CanisterRequest) -> HttpGatewayResult<HttpRequest> {
+pub(crate) fn convert_request(
+    request: &CanisterRequest,
+    request_header_policy: &RequestHeaderPolicy,
+    url_normalization: UrlNormalization,
+    invalid_header_value_action: InvalidHeaderValueAction,
+    invalid_header_values: &mut Vec<InvalidHeaderValue>,
+) -> HttpGatewayResult<HttpRequest> {
     let uri = request.uri();
-    let mut url = uri.path().to_string();
-    if let Some(query) = uri.query() {
-        url.push('?');
-        url.push_str(query);
+    let url = match url_normalization {
+        UrlNormalization::Disabled => {
+            let mut url = uri.path().to_string();
+            if let Some(query) = uri.query() {
+                url.push('?');
+                url.push_str(query);
+            }
+
+            url
+        }
+        UrlNormalization::Rfc3986 => normalize_url(uri)?,
+    };
+
+    let mut headers = vec![];
+    for (name, value) in request.headers() {
+        if let Some(value) = convert_request_header_value(
+            name.as_str(),
+            value,
+            invalid_header_value_action,
+            invalid_header_values,
+        )? {
+            headers.push((name.to_string(), value));
+        }
+    }
+
+    // the authority of an absolute-form request target takes precedence over the `Host` header
+    if let (UrlNormalization::Rfc3986, Some(authority)) = (url_normalization, uri.authority()) {
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case(HOST_HEADER_NAME));
+        headers.push((HOST_HEADER_NAME.to_string(), authority.to_string()));
     }
 
     Ok(HttpRequest {
         method: request.method().to_string(),
         url,
-        headers: request
-            .headers()
-            .into_iter()
-            .map(|(name, value)| {
-                Ok((
-                    name.to_string(),
-                    value
-                        .to_str()
-                        .map_err(|_| HttpGatewayError::HeaderValueParsingError {
-                            header_name: name.to_string(),
-                            header_value: String::from_utf8_lossy(value.as_bytes()).to_string(),
-                        })?
-                        .to_string(),
-                ))
-            })
-            .collect::<HttpGatewayResult<Vec<_>>>()?,
+        headers: request_header_policy.apply(headers)?,
         body: request.body().to_vec(),
     })
 }
 
+/// Adds the `identity` encoding to the value of an `accept-encoding` header if it is missing,
+/// so that canisters can always fall back to an uncompressed response.
+pub(crate) fn add_identity_encoding(accept_encoding: &str) -> String {
+    let mut encodings = accept_encoding
+        .split(',')
+        .map(|s| s.trim())
+        .collect::<Vec<_>>();
+    if !encodings.iter().any(|s| s.eq_ignore_ascii_case("identity")) {
+        encodings.push("identity");
+    };
+
+    encodings.join(", ")
+}
+
 pub async fn process_request(
     agent: &Agent,
-    request: CanisterRequest,
-    canister_id: Principal,
+    query_agent: &Agent,
+    args: &HttpGatewayRequestBuilderArgs<'_>,
     skip_verification: bool,
 ) -> HttpGatewayResponse {
-    let http_request = match convert_request(request) {
+    let &HttpGatewayRequestBuilderArgs {
+        request_args:
+            HttpGatewayRequestArgs {
+                ref canister_request,
+                canister_id,
+                ref client_info,
+                identity: _,
+            },
+        endpoint_pool: _,
+        subnet_routing: _,
+        response_header_sanitizer,
+        forwarding_headers,
+        request_header_policy,
+        url_normalization,
+        invalid_header_value_action,
+        async_update_calls,
+        upgrade_policy,
+        direct_update_calls,
+        streaming_prefetch,
+        streaming_limits,
+        streaming_callback_policy,
+        verify_query_signatures,
+    } = args;
+
+    let integrity_trailers = accepts_trailers(canister_request.headers());
+
+    let mut metadata = HttpGatewayResponseMetadata {
+        upgraded_to_update_call: false,
+        direct_update_call: false,
+        response_verification_version: None,
+        update_response_verified: false,
+        query_signature_verified: false,
+        internal_error: None,
+        endpoint: None,
+        subnet_endpoint: None,
+        failed_endpoints: vec![],
+        invalid_header_values: vec![],
+    };
+
+    let mut http_request = match convert_request(
+        canister_request,
+        request_header_policy,
+        url_normalization,
+        invalid_header_value_action,
+        &mut metadata.invalid_header_values,
+    ) {
         Ok(http_request) => http_request,
         Err(e) => {
+            let status_code = match e {
+                HttpGatewayError::RequestHeadersTooLarge { .. } => {
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                }
+                _ => StatusCode::BAD_REQUEST,
+            };
+
             return HttpGatewayResponse {
                 canister_response: create_err_response(
-                    StatusCode::BAD_REQUEST,
+                    status_code,
                     &format!("Failed to parse request: {}", e),
                 ),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: false,
-                    response_verification_version: None,
                     internal_error: Some(e),
+                    ..metadata
                 },
-            }
+            };
         }
     };
 
+    if let (Some(forwarding_headers), Some(client_info)) = (forwarding_headers, client_info) {
+        forwarding_headers.apply(&mut http_request.headers, client_info);
+    }
+
     let canister = HttpRequestCanister::create(agent, canister_id);
     let header_fields = http_request
         .headers
@@ -88,13 +193,7 @@ pub async fn process_request(
         .filter(|(name, _)| name != "x-request-id")
         .map(|(name, value)| {
             if name.eq_ignore_ascii_case(ACCEPT_ENCODING_HEADER_NAME) {
-                let mut encodings = value.split(',').map(|s| s.trim()).collect::<Vec<_>>();
-                if !encodings.iter().any(|s| s.eq_ignore_ascii_case("identity")) {
-                    encodings.push("identity");
-                };
-
-                let value = encodings.join(", ");
-                return HeaderField(name.into(), value.into());
+                return HeaderField(name.into(), add_identity_encoding(value).into());
             }
 
             HeaderField(name.into(), value.into())
@@ -102,62 +201,197 @@ pub async fn process_request(
         .collect::<Vec<HeaderField>>()
         .into_iter();
 
-    let query_result = canister
-        .http_request_custom(
-            &http_request.method,
-            &http_request.url,
-            header_fields.clone(),
-            &http_request.body,
-            Some(&u16::from(MAX_VERIFICATION_VERSION)),
-        )
-        .call()
-        .await;
+    let status_token = async_update_calls
+        .and_then(|async_update_calls| async_update_calls.parse_status_url(&http_request.url))
+        .map(|token| token.to_string());
 
-    let agent_response = match query_result {
-        Ok((response,)) => response,
-        Err(e) => {
-            return HttpGatewayResponse {
-                canister_response: handle_agent_error(&e),
-                metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: false,
-                    response_verification_version: None,
-                    internal_error: Some(e.into()),
-                },
-            };
-        }
-    };
+    let mut agent_response = if let (Some(async_update_calls), Some(status_token)) =
+        (async_update_calls, status_token)
+    {
+        metadata.upgraded_to_update_call = true;
+
+        match poll_async_update_call(agent, canister_id, async_update_calls, &status_token).await {
+            Ok(response) => {
+                metadata.update_response_verified = true;
 
-    let is_update_call = agent_response.upgrade == Some(true);
-    let agent_response = if is_update_call {
-        let update_result = canister
-            .http_request_update_custom(
+                response
+            }
+            Err((canister_response, internal_error)) => {
+                return HttpGatewayResponse {
+                    canister_response,
+                    metadata: HttpGatewayResponseMetadata {
+                        internal_error,
+                        ..metadata
+                    },
+                };
+            }
+        }
+    } else {
+        metadata.direct_update_call = direct_update_calls.is_some_and(|direct_update_calls| {
+            direct_update_calls.should_call_directly(
+                &canister_id,
                 &http_request.method,
                 &http_request.url,
-                header_fields.clone(),
-                &http_request.body,
             )
-            .call_and_wait()
+        });
+
+        let query_response = if metadata.direct_update_call {
+            None
+        } else {
+            let query_result = http_request_query(
+                query_agent,
+                canister_id,
+                &HttpRequestArg {
+                    method: &http_request.method,
+                    url: &http_request.url,
+                    headers: header_fields.clone().collect(),
+                    body: &http_request.body,
+                    certificate_version: Some(&u16::from(MAX_VERIFICATION_VERSION)),
+                },
+                verify_query_signatures,
+            )
             .await;
 
-        match update_result {
-            Ok((response,)) => response,
-            Err(e) => {
-                return HttpGatewayResponse {
-                    canister_response: handle_agent_error(&e),
-                    metadata: HttpGatewayResponseMetadata {
-                        upgraded_to_update_call: true,
-                        response_verification_version: None,
-                        internal_error: Some(e.into()),
+            match query_result {
+                Ok((response, signatures_verified)) => {
+                    metadata.query_signature_verified = signatures_verified;
+
+                    Some(response)
+                }
+                Err(e) => {
+                    return HttpGatewayResponse {
+                        canister_response: handle_agent_error(&e),
+                        metadata: HttpGatewayResponseMetadata {
+                            internal_error: Some(e.into()),
+                            ..metadata
+                        },
+                    };
+                }
+            }
+        };
+
+        metadata.upgraded_to_update_call = query_response
+            .as_ref()
+            .map_or(true, |response| response.upgrade == Some(true));
+
+        match query_response {
+            Some(query_response) if !metadata.upgraded_to_update_call => query_response,
+            _ => {
+                if let Err(e) =
+                    upgrade_policy.check(&canister_id, &http_request.method, &http_request.url)
+                {
+                    let status_code = match e {
+                        HttpGatewayError::UpdateCallUpgradeRateLimited { .. } => {
+                            StatusCode::TOO_MANY_REQUESTS
+                        }
+                        _ => StatusCode::FORBIDDEN,
+                    };
+
+                    return HttpGatewayResponse {
+                        canister_response: create_err_response(status_code, &e.to_string()),
+                        metadata: HttpGatewayResponseMetadata {
+                            internal_error: Some(e),
+                            ..metadata
+                        },
+                    };
+                }
+
+                // remember routes that were upgraded by the canister to call them directly next time,
+                // only once the upgrade is allowed so that denied routes keep being queried first
+                if let Some(direct_update_calls) =
+                    direct_update_calls.filter(|_| !metadata.direct_update_call)
+                {
+                    direct_update_calls.record_upgrade(
+                        &canister_id,
+                        &http_request.method,
+                        &http_request.url,
+                    );
+                }
+
+                let update_call = canister.http_request_update_custom(
+                    &http_request.method,
+                    &http_request.url,
+                    header_fields.clone(),
+                    &http_request.body,
+                );
+
+                let update_result = match async_update_calls {
+                    Some(async_update_calls) => match update_call.call().await {
+                        Ok(request_id) => {
+                            match async_update_calls.insert(canister_id, request_id) {
+                                Some(token) => {
+                                    return HttpGatewayResponse {
+                                        canister_response: create_accepted_response(
+                                            async_update_calls,
+                                            &token,
+                                        ),
+                                        metadata,
+                                    };
+                                }
+                                // the store of in-flight update calls is full, answer synchronously
+                                None => wait_certified(agent, request_id, canister_id).await,
+                            }
+                        }
+                        Err(e) => Err(e),
                     },
+                    None => call_and_wait_certified(agent, canister_id, update_call).await,
                 };
+
+                match update_result {
+                    Ok(response) => {
+                        metadata.update_response_verified = true;
+
+                        response
+                    }
+                    Err(e) => {
+                        return HttpGatewayResponse {
+                            canister_response: handle_agent_error(&e),
+                            metadata: HttpGatewayResponseMetadata {
+                                internal_error: Some(e.into()),
+                                ..metadata
+                            },
+                        };
+                    }
+                }
             }
         }
-    } else {
-        agent_response
     };
+    let is_update_call = metadata.upgraded_to_update_call;
+    let response_status_code = agent_response.status_code;
+    let response_headers = std::mem::take(&mut agent_response.headers);
 
-    let response_body = match get_body_and_streaming_body(agent, &agent_response).await {
+    // streaming callbacks are query calls
+    let response_body = match get_body_and_streaming_body(
+        query_agent,
+        &canister_id,
+        agent_response,
+        StreamingBodyOptions {
+            streaming_prefetch,
+            streaming_limits,
+            streaming_callback_policy,
+            verify_query_signatures,
+            integrity_trailers,
+        },
+    )
+    .await
+    {
         Ok(response_body) => response_body,
+        // the response of the canister is not acceptable, as opposed to the gateway failing to process it
+        Err(
+            e @ (HttpGatewayError::StreamingCallbackCanisterNotAllowed { .. }
+            | HttpGatewayError::ResponseBodyStreamError(
+                ResponseBodyStreamError::BodySizeLimitExceeded { .. }
+                | ResponseBodyStreamError::VerificationFailed(_),
+            )),
+        ) => {
+            return HttpGatewayResponse {
+                canister_response: create_err_response(StatusCode::BAD_GATEWAY, &e.to_string()),
+                metadata: HttpGatewayResponseMetadata {
+                    internal_error: Some(e),
+                    ..metadata
+                },
+            }
+        }
         Err(e) => {
             return HttpGatewayResponse {
                 canister_response: create_err_response(
@@ -165,67 +399,65 @@ pub async fn process_request(
                     &format!("Failed to parse response body: {}", e),
                 ),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: is_update_call,
-                    response_verification_version: None,
-                    internal_error: Some(e.into()),
+                    internal_error: Some(e),
+                    ..metadata
                 },
             }
         }
     };
 
     // there is no need to verify the response if the request was upgraded to an update call
-    let validation_info = if !is_update_call {
+    let (response_body, validation_info) = match response_body {
         // At the moment verification is only performed if the response is not using a streaming
         // strategy. Performing verification for those requests would required to join all the chunks
         // and this could cause memory issues and possibly create DOS attack vectors.
-        match &response_body {
-            Either::Right(body) => {
-                // this unwrap should never panic because `Either::Right` will always have a full body
-                let body = body.clone().collect().await.unwrap().to_bytes().to_vec();
-
-                let validation_result = validate(
-                    agent,
-                    &canister_id,
-                    http_request,
-                    HttpResponse {
-                        status_code: agent_response.status_code,
-                        headers: agent_response
-                            .headers
-                            .iter()
-                            .map(|HeaderField(k, v)| (k.to_string(), v.to_string()))
-                            .collect(),
-                        body,
-                        upgrade: None,
-                    },
-                    skip_verification,
-                );
+        Either::Right(body) if !is_update_call => {
+            // this unwrap should never panic because `Either::Right` will always have a full body,
+            // collecting a full body returns its bytes without copying them
+            let body = body.collect().await.unwrap().to_bytes();
 
-                match validation_result {
-                    Err(e) => {
-                        return HttpGatewayResponse {
-                            canister_response: create_err_response(
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                &format!("Response verification failed: {}", e),
-                            ),
-                            metadata: HttpGatewayResponseMetadata {
-                                upgraded_to_update_call: is_update_call,
-                                response_verification_version: None,
-                                internal_error: Some(e),
-                            },
-                        };
-                    }
-                    Ok(validation_info) => validation_info,
+            let validation_result = validate(
+                agent,
+                &canister_id,
+                http_request,
+                HttpResponse {
+                    status_code: response_status_code,
+                    headers: response_headers
+                        .iter()
+                        .map(|HeaderField(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                    // the bytes are not shared, so they are converted without copying them
+                    body: Vec::from(body),
+                    upgrade: None,
+                },
+                skip_verification,
+            );
+
+            match validation_result {
+                Err(e) => {
+                    return HttpGatewayResponse {
+                        canister_response: create_err_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &format!("Response verification failed: {}", e),
+                        ),
+                        metadata: HttpGatewayResponseMetadata {
+                            internal_error: Some(e),
+                            ..metadata
+                        },
+                    };
+                }
+                Ok((validation_info, body)) => {
+                    (Either::Right(Full::new(Bytes::from(body))), validation_info)
                 }
             }
-            _ => None,
         }
-    } else {
-        None
+        response_body => (response_body, None),
     };
 
-    let response_verification_version = validation_info.as_ref().map(|e| e.verification_version);
+    metadata.response_verification_version =
+        validation_info.as_ref().map(|e| e.verification_version);
 
-    let status_code = match StatusCode::from_u16(agent_response.status_code) {
+    let status_code = match StatusCode::from_u16(response_status_code) {
         Ok(status_code) => status_code,
         Err(e) => {
             return HttpGatewayResponse {
@@ -234,67 +466,66 @@ pub async fn process_request(
                     &format!("Failed to parse response status code: {}", e),
                 ),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: is_update_call,
-                    response_verification_version,
                     internal_error: Some(e.into()),
+                    ..metadata
                 },
             }
         }
     };
 
-    let mut response_builder = Response::builder().status(status_code);
+    // status codes are not certified in v1, reject known dangerous status codes
+    if let Some(validation_info) = &validation_info {
+        if validation_info.verification_version < 2
+            && response_status_code >= 300
+            && response_status_code < 400
+        {
+            return HttpGatewayResponse {
+                canister_response: create_err_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Response verification v1 does not allow redirects",
+                ),
+                metadata,
+            };
+        }
+    }
 
-    match &validation_info {
-        // if there is no validation info, that means we've skipped verification,
-        // this should only happen for raw domains, or for responses that are too
-        // large to verify, return response as-is
-        None => {
-            for HeaderField(name, value) in &agent_response.headers {
-                response_builder = response_builder.header(name.as_ref(), value.as_ref());
+    let mut response_builder = Response::builder().status(status_code);
+    for (name, value) in get_response_headers(
+        &canister_id,
+        &response_headers,
+        validation_info.as_ref(),
+        response_header_sanitizer,
+    ) {
+        match convert_response_header_value(
+            &name,
+            &value,
+            invalid_header_value_action,
+            &mut metadata.invalid_header_values,
+        ) {
+            Ok(Some(value)) => {
+                response_builder = response_builder.header(name, value);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                return HttpGatewayResponse {
+                    canister_response: create_err_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("Failed to parse response header: {}", e),
+                    ),
+                    metadata: HttpGatewayResponseMetadata {
+                        internal_error: Some(e),
+                        ..metadata
+                    },
+                };
             }
         }
+    }
 
-        Some(validation_info) => {
-            if validation_info.verification_version < 2 {
-                // status codes are not certified in v1, reject known dangerous status codes
-                if agent_response.status_code >= 300 && agent_response.status_code < 400 {
-                    return HttpGatewayResponse {
-                        canister_response: create_err_response(
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Response verification v1 does not allow redirects",
-                        ),
-                        metadata: HttpGatewayResponseMetadata {
-                            upgraded_to_update_call: is_update_call,
-                            response_verification_version,
-                            internal_error: None,
-                        },
-                    };
-                }
-
-                // headers are also not certified in v1, filter known dangerous headers
-                for HeaderField(name, value) in &agent_response.headers {
-                    if !name.eq_ignore_ascii_case(CACHE_HEADER_NAME) {
-                        response_builder = response_builder.header(name.as_ref(), value.as_ref());
-                    }
-                }
-            } else {
-                match &validation_info.response {
-                    // if there is no response, the canister has decided to certifiably skip verification,
-                    // assume the developer knows what they're doing and return the response as-is
-                    None => {
-                        for HeaderField(name, value) in &agent_response.headers {
-                            response_builder =
-                                response_builder.header(name.as_ref(), value.as_ref());
-                        }
-                    }
-                    // if there is a response, the canister has decided to certify some (but not necessarily all) headers,
-                    // return only the certified headers
-                    Some(certified_http_response) => {
-                        for (name, value) in &certified_http_response.headers {
-                            response_builder = response_builder.header(name, value);
-                        }
-                    }
-                }
+    // integrity trailers are only added to streamed bodies
+    if integrity_trailers && matches!(response_body, Either::Left(_)) {
+        if let Some(headers) = response_builder.headers_mut() {
+            if let Ok(trailer_names) = integrity_trailer_names().parse() {
+                headers.insert(header::TRAILER, trailer_names);
             }
         }
     }
@@ -308,9 +539,8 @@ pub async fn process_request(
                     &format!("Failed to build response: {}", e),
                 ),
                 metadata: HttpGatewayResponseMetadata {
-                    upgraded_to_update_call: is_update_call,
-                    response_verification_version,
                     internal_error: Some(e.into()),
+                    ..metadata
                 },
             }
         }
@@ -318,10 +548,132 @@ pub async fn process_request(
 
     HttpGatewayResponse {
         canister_response: response,
+        metadata,
+    }
+}
+
+fn get_response_headers(
+    canister_id: &Principal,
+    agent_response_headers: &[HeaderField],
+    validation_info: Option<&VerificationInfo>,
+    response_header_sanitizer: &ResponseHeaderSanitizer,
+) -> Vec<(String, String)> {
+    let agent_response_headers = agent_response_headers
+        .iter()
+        .map(|HeaderField(name, value)| (name.as_ref(), value.as_ref()));
+
+    match validation_info {
+        // if there is no validation info, that means we've skipped verification,
+        // this should only happen for raw domains, for responses that are too
+        // large to verify, or for responses to update calls, whose certificate does
+        // not cover the headers of streamed chunks, return the sanitized headers
+        None => response_header_sanitizer.sanitize(canister_id, agent_response_headers),
+
+        // headers are not certified in v1, filter known dangerous headers
+        Some(validation_info) if validation_info.verification_version < 2 => {
+            response_header_sanitizer.sanitize(
+                canister_id,
+                agent_response_headers
+                    .filter(|(name, _)| !name.eq_ignore_ascii_case(CACHE_HEADER_NAME)),
+            )
+        }
+
+        Some(validation_info) => match &validation_info.response {
+            // if there is no response, the canister has decided to certifiably skip verification,
+            // assume the developer knows what they're doing and return the sanitized headers
+            None => response_header_sanitizer.sanitize(canister_id, agent_response_headers),
+
+            // if there is a response, the canister has decided to certify some (but not necessarily all) headers,
+            // return only the certified headers
+            Some(certified_http_response) => certified_http_response.headers.clone(),
+        },
+    }
+}
+
+fn create_accepted_response(
+    async_update_calls: &AsyncUpdateCalls,
+    token: &str,
+) -> CanisterResponse {
+    let mut response = create_success_response(
+        StatusCode::ACCEPTED,
+        "The update call is in progress, poll the URL in the Location header for its result",
+    );
+
+    let headers = response.headers_mut();
+    if let Ok(location) = async_update_calls.status_url(token).parse() {
+        headers.insert(header::LOCATION, location);
+    }
+    headers.insert(
+        header::RETRY_AFTER,
+        async_update_calls.retry_after().as_secs().max(1).into(),
+    );
+
+    response
+}
+
+/// Reads the status of an in-flight update call and returns its certified response,
+/// or the response to return to the client if the call has not been answered yet.
+async fn poll_async_update_call(
+    agent: &Agent,
+    canister_id: Principal,
+    async_update_calls: &AsyncUpdateCalls,
+    token: &str,
+) -> Result<AgentResponseAny, (CanisterResponse, Option<HttpGatewayError>)> {
+    let Some(request_id) = async_update_calls.get(&canister_id, token) else {
+        return Err((
+            create_err_response(StatusCode::NOT_FOUND, "Unknown update call"),
+            None,
+        ));
+    };
+
+    // `request_status_raw` verifies the `read_state` certificate in the same way as `wait`,
+    // so a reply is taken from the certified state tree
+    let status = match agent.request_status_raw(&request_id, canister_id).await {
+        Ok(status) => status,
+        Err(e) => return Err((handle_agent_error(&e), Some(e.into()))),
+    };
+
+    match status {
+        RequestStatusResponse::Unknown
+        | RequestStatusResponse::Received
+        | RequestStatusResponse::Processing => {
+            Err((create_accepted_response(async_update_calls, token), None))
+        }
+
+        RequestStatusResponse::Replied(ReplyResponse { arg }) => {
+            async_update_calls.remove(token);
+
+            decode_update_reply(&arg).map_err(|e| (handle_agent_error(&e), Some(e.into())))
+        }
+
+        RequestStatusResponse::Rejected(reject_response) => {
+            async_update_calls.remove(token);
+
+            let e = AgentError::CertifiedReject(reject_response);
+            Err((handle_agent_error(&e), Some(e.into())))
+        }
+
+        RequestStatusResponse::Done => {
+            async_update_calls.remove(token);
+
+            Err((
+                create_err_response(
+                    StatusCode::GONE,
+                    "The response of the update call is no longer available",
+                ),
+                None,
+            ))
+        }
+    }
+}
+
+/// Creates the response for a request that failed with `error` before it was sent to the canister.
+pub(crate) fn create_agent_error_response(error: AgentError) -> HttpGatewayResponse {
+    HttpGatewayResponse {
+        canister_response: handle_agent_error(&error),
         metadata: HttpGatewayResponseMetadata {
-            upgraded_to_update_call: is_update_call,
-            response_verification_version,
-            internal_error: None,
+            internal_error: Some(error.into()),
+            ..Default::default()
         },
     }
 }
@@ -377,7 +729,11 @@ fn handle_agent_error(error: &AgentError) -> CanisterResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use http::Request;
+    use crate::HeaderDirection;
+    use http::{HeaderValue, Request};
+    use ic_response_verification::types::VerifiedResponse;
+    use proptest::prelude::*;
+    use std::{collections::BTreeMap, time::Duration};
 
     #[test]
     fn test_convert_request() {
@@ -388,7 +744,14 @@ mod tests {
             .body(b"body".to_vec())
             .unwrap();
 
-        let http_request = convert_request(request).unwrap();
+        let http_request = convert_request(
+            &request,
+            &RequestHeaderPolicy::default(),
+            UrlNormalization::Disabled,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        )
+        .unwrap();
 
         assert_eq!(
             http_request,
@@ -406,4 +769,452 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_convert_request_with_header_policy() {
+        let request = Request::builder()
+            .uri("http://example.com/foo")
+            .header("Accept", "text/html")
+            .header("Authorization", "Bearer edge-token")
+            .header("Cookie", "session=1")
+            .header("X-Trace", "abc")
+            .header("X-Client", "legacy")
+            .body(vec![])
+            .unwrap();
+        let request_header_policy = RequestHeaderPolicy::new()
+            .with_dropped_header("Authorization")
+            .with_renamed_header("x-trace", "x-canister-trace")
+            .with_set_header("cookie", "consent=1")
+            .with_set_header("x-gateway", "ic-http-gateway");
+
+        let http_request = convert_request(
+            &request,
+            &request_header_policy,
+            UrlNormalization::Disabled,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            http_request.headers,
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                ("x-canister-trace".to_string(), "abc".to_string()),
+                ("x-client".to_string(), "legacy".to_string()),
+                ("cookie".to_string(), "consent=1".to_string()),
+                ("x-gateway".to_string(), "ic-http-gateway".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_request_with_header_allowlist() {
+        let request = Request::builder()
+            .uri("http://example.com/foo")
+            .header("Accept", "text/html")
+            .header("Accept-Encoding", "gzip")
+            .header("Cookie", "session=1")
+            .body(vec![])
+            .unwrap();
+        let request_header_policy = RequestHeaderPolicy::new()
+            .with_allowed_header("accept")
+            .with_allowed_header("Accept-Encoding");
+
+        let http_request = convert_request(
+            &request,
+            &request_header_policy,
+            UrlNormalization::Disabled,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            http_request.headers,
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                ("accept-encoding".to_string(), "gzip".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_request_with_max_total_header_bytes() {
+        let request = Request::builder()
+            .uri("http://example.com/foo")
+            .header("Accept", "text/html")
+            .header("Traceparent", "0".repeat(64))
+            .body(vec![])
+            .unwrap();
+        let request_header_policy = RequestHeaderPolicy::new().with_max_total_header_bytes(32);
+
+        let result = convert_request(
+            &request,
+            &request_header_policy,
+            UrlNormalization::Disabled,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        );
+
+        assert!(matches!(
+            result,
+            Err(HttpGatewayError::RequestHeadersTooLarge {
+                total_header_bytes: 90,
+                max_total_header_bytes: 32,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_convert_request_with_url_normalization() {
+        let request = Request::builder()
+            .uri("https://example.com:8443/assets/%2e%2e/%7eadmin/./index.html?q=%41")
+            .header("Host", "attacker.example")
+            .body(vec![])
+            .unwrap();
+
+        let http_request = convert_request(
+            &request,
+            &RequestHeaderPolicy::default(),
+            UrlNormalization::Rfc3986,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        )
+        .unwrap();
+
+        assert_eq!(http_request.url, "/~admin/index.html?q=A");
+        assert_eq!(
+            http_request.headers,
+            vec![("host".to_string(), "example.com:8443".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_convert_request_rejects_malformed_url() {
+        let request = Request::builder().uri("/foo%00bar").body(vec![]).unwrap();
+
+        let result = convert_request(
+            &request,
+            &RequestHeaderPolicy::default(),
+            UrlNormalization::Rfc3986,
+            InvalidHeaderValueAction::Reject,
+            &mut vec![],
+        );
+
+        assert!(matches!(
+            result,
+            Err(HttpGatewayError::MalformedRequestUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn test_convert_request_with_invalid_header_values() {
+        let request = Request::builder()
+            .uri("/upload")
+            .header("Accept", "text/html")
+            .header(
+                "Content-Disposition",
+                HeaderValue::from_bytes(b"attachment; filename=\"r\xe9sum\xe9.pdf\"").unwrap(),
+            )
+            .body(vec![])
+            .unwrap();
+        let mut invalid_header_values = vec![];
+
+        let http_request = convert_request(
+            &request,
+            &RequestHeaderPolicy::default(),
+            UrlNormalization::Disabled,
+            InvalidHeaderValueAction::Transcode,
+            &mut invalid_header_values,
+        )
+        .unwrap();
+
+        assert_eq!(
+            http_request.headers,
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                (
+                    "content-disposition".to_string(),
+                    "attachment; filename=\"résumé.pdf\"".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            invalid_header_values,
+            vec![InvalidHeaderValue {
+                header_name: "content-disposition".to_string(),
+                direction: HeaderDirection::Request,
+                action: InvalidHeaderValueAction::Transcode,
+            }]
+        );
+    }
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn agent_response_headers() -> Vec<HeaderField<'static>> {
+        vec![
+            HeaderField("content-type".into(), "text/html".into()),
+            HeaderField("cache-control".into(), "max-age=600".into()),
+            HeaderField("set-cookie".into(), "session=1".into()),
+            HeaderField("transfer-encoding".into(), "chunked".into()),
+        ]
+    }
+
+    #[test]
+    fn test_get_response_headers_skipped_verification() {
+        let headers = get_response_headers(
+            &canister_id(),
+            &agent_response_headers(),
+            None,
+            &ResponseHeaderSanitizer::strict(),
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("cache-control".to_string(), "max-age=600".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_response_headers_v1() {
+        let validation_info = VerificationInfo {
+            response: None,
+            verification_version: 1,
+        };
+
+        let headers = get_response_headers(
+            &canister_id(),
+            &agent_response_headers(),
+            Some(&validation_info),
+            &ResponseHeaderSanitizer::new(),
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("set-cookie".to_string(), "session=1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_response_headers_v2_certified_skip() {
+        let validation_info = VerificationInfo {
+            response: None,
+            verification_version: 2,
+        };
+
+        let headers = get_response_headers(
+            &canister_id(),
+            &agent_response_headers(),
+            Some(&validation_info),
+            &ResponseHeaderSanitizer::strict()
+                .with_allowed_header_for_canister(canister_id(), "set-cookie")
+                .with_enforced_header("x-content-type-options", "nosniff"),
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("cache-control".to_string(), "max-age=600".to_string()),
+                ("set-cookie".to_string(), "session=1".to_string()),
+                ("x-content-type-options".to_string(), "nosniff".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_accepted_response() {
+        let async_update_calls = AsyncUpdateCalls::new()
+            .with_status_path_prefix("/_/calls")
+            .with_retry_after(Duration::from_secs(3));
+
+        let response = create_accepted_response(&async_update_calls, "ab");
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            response.headers().get(header::LOCATION),
+            Some(&HeaderValue::from_static("/_/calls/ab"))
+        );
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER),
+            Some(&HeaderValue::from_static("3"))
+        );
+    }
+
+    #[test]
+    fn test_get_response_headers_update() {
+        // responses to update calls carry no verification info and are always sanitized
+        let headers = get_response_headers(
+            &canister_id(),
+            &agent_response_headers(),
+            None,
+            &ResponseHeaderSanitizer::strict().with_enforced_header("x-frame-options", "DENY"),
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("cache-control".to_string(), "max-age=600".to_string()),
+                ("x-frame-options".to_string(), "DENY".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_response_headers_v2_certified() {
+        let certified_headers = vec![
+            ("content-type".to_string(), "text/html".to_string()),
+            ("set-cookie".to_string(), "session=1".to_string()),
+        ];
+        let validation_info = VerificationInfo {
+            response: Some(VerifiedResponse {
+                status_code: Some(200),
+                headers: certified_headers.clone(),
+                body: vec![],
+            }),
+            verification_version: 2,
+        };
+
+        let headers = get_response_headers(
+            &canister_id(),
+            &agent_response_headers(),
+            Some(&validation_info),
+            &ResponseHeaderSanitizer::strict().with_enforced_header("x-frame-options", "DENY"),
+        );
+
+        assert_eq!(headers, certified_headers);
+    }
+
+    fn url_normalization() -> impl Strategy<Value = UrlNormalization> {
+        prop_oneof![
+            Just(UrlNormalization::Disabled),
+            Just(UrlNormalization::Rfc3986)
+        ]
+    }
+
+    /// Returns a path and query that are valid in a request target.
+    fn path_and_query() -> impl Strategy<Value = (String, Option<String>)> {
+        (
+            "(/[a-zA-Z0-9._~!$&'()*+,;=:@%-]{0,12}){1,6}",
+            proptest::option::of("[a-zA-Z0-9._~!$&'()*+,;=:@/?%-]{0,24}"),
+        )
+    }
+
+    /// Returns headers with distinct, lowercase names and visible ASCII values.
+    fn headers() -> impl Strategy<Value = BTreeMap<String, String>> {
+        proptest::collection::btree_map("x-[a-z0-9-]{1,16}", "[!-~]([ -~]{0,30}[!-~])?", 0..8)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_convert_request_never_panics(
+            (path, query) in path_and_query(),
+            header_values in proptest::collection::vec(
+                proptest::collection::vec(any::<u8>(), 0..32),
+                0..4,
+            ),
+            url_normalization in url_normalization(),
+        ) {
+            let uri = match query {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut request = Request::builder().uri(uri.as_str());
+            for (i, value) in header_values.iter().enumerate() {
+                if let Ok(value) = HeaderValue::from_bytes(value) {
+                    request = request.header(format!("x-header-{}", i), value);
+                }
+            }
+            let Ok(request) = request.body(vec![]) else {
+                return Ok(());
+            };
+
+            let _ = convert_request(
+                &request,
+                &RequestHeaderPolicy::default(),
+                url_normalization,
+                InvalidHeaderValueAction::Transcode,
+                &mut vec![],
+            );
+        }
+
+        #[test]
+        fn prop_convert_request_round_trips_url_and_headers(
+            (path, query) in path_and_query(),
+            headers in headers(),
+            body in proptest::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let url = match query {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut request = Request::builder().method("POST").uri(url.as_str());
+            for (name, value) in &headers {
+                request = request.header(name, value);
+            }
+            let request = request.body(body.clone()).unwrap();
+
+            let http_request = convert_request(
+                &request,
+                &RequestHeaderPolicy::default(),
+                UrlNormalization::Disabled,
+                InvalidHeaderValueAction::Reject,
+                &mut vec![],
+            )
+            .unwrap();
+
+            prop_assert_eq!(http_request.method, "POST");
+            prop_assert_eq!(http_request.url, url);
+            prop_assert_eq!(
+                http_request.headers.into_iter().collect::<BTreeMap<_, _>>(),
+                headers
+            );
+            prop_assert_eq!(http_request.body, body);
+        }
+
+        #[test]
+        fn prop_add_identity_encoding(
+            encodings in proptest::collection::vec("[a-zA-Z*-]{0,10}(;q=[01](\\.[0-9])?)?", 1..6),
+            separator in "[ ]{0,2},[ ]{0,2}",
+        ) {
+            let accept_encoding = encodings.join(&separator);
+
+            let value = add_identity_encoding(&accept_encoding);
+            let tokens = value.split(", ").collect::<Vec<_>>();
+
+            let trimmed_encodings = encodings.iter().map(|s| s.trim()).collect::<Vec<_>>();
+            if trimmed_encodings.iter().any(|s| s.eq_ignore_ascii_case("identity")) {
+                prop_assert_eq!(tokens, trimmed_encodings);
+            } else {
+                prop_assert_eq!(&tokens[..tokens.len() - 1], &trimmed_encodings[..]);
+                prop_assert_eq!(tokens[tokens.len() - 1], "identity");
+            }
+
+            // adding the identity encoding again does not change the value
+            prop_assert_eq!(add_identity_encoding(&value), value);
+        }
+
+        #[test]
+        fn prop_create_err_response(status_code in 100u16..1000, msg in ".*") {
+            let status_code = StatusCode::from_u16(status_code).unwrap();
+
+            let response = create_err_response(status_code, &msg);
+
+            prop_assert_eq!(response.status(), status_code);
+            let body = futures::executor::block_on(response.into_body().collect())
+                .unwrap()
+                .to_bytes();
+            prop_assert_eq!(body.as_ref(), msg.as_bytes());
+        }
+    }
 }
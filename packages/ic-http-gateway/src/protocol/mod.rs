@@ -3,3 +3,9 @@ pub(crate) use handler::*;
 
 mod validate;
 pub(crate) use validate::*;
+
+mod update_call;
+pub(crate) use update_call::*;
+
+mod query_call;
+pub(crate) use query_call::*;
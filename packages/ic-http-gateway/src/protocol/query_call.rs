@@ -0,0 +1,121 @@
+use crate::AgentResponseAny;
+use candid::{types::value::IDLArgs, CandidType, Principal};
+use ic_agent::{Agent, AgentError};
+use ic_utils::interfaces::http_request::{
+    HeaderField, HttpRequestStreamingCallbackAny, StreamingCallbackHttpResponse, Token,
+};
+
+/// The argument of the `http_request` query method.
+#[derive(CandidType)]
+pub(crate) struct HttpRequestArg<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub headers: Vec<HeaderField<'a>>,
+    pub body: &'a [u8],
+    pub certificate_version: Option<&'a u16>,
+}
+
+/// Calls the `http_request` query method, see [query].
+/// Returns the response and whether the signatures on it were verified.
+pub(crate) async fn http_request_query(
+    agent: &Agent,
+    canister_id: Principal,
+    arg: &HttpRequestArg<'_>,
+    verify_signatures: bool,
+) -> Result<(AgentResponseAny, bool), AgentError> {
+    let arg = candid::encode_one(arg).map_err(|e| AgentError::CandidError(Box::new(e)))?;
+    let (reply, signatures_verified) =
+        query(agent, canister_id, "http_request", arg, verify_signatures).await?;
+
+    let response = candid::decode_one(&reply).map_err(|e| AgentError::CandidError(Box::new(e)))?;
+
+    Ok((response, signatures_verified))
+}
+
+/// Calls the streaming callback of a response with `token`, see [query].
+/// Returns the next chunk of the body and the token to fetch the chunk after it, if any.
+pub(crate) async fn stream_callback_query(
+    agent: &Agent,
+    callback: &HttpRequestStreamingCallbackAny,
+    token: Token,
+    verify_signatures: bool,
+) -> Result<(Vec<u8>, Option<Token>), AgentError> {
+    let arg = IDLArgs::new(&[token.0])
+        .to_bytes()
+        .map_err(|e| AgentError::CandidError(Box::new(e)))?;
+    let (reply, _) = query(
+        agent,
+        callback.0.principal,
+        &callback.0.method,
+        arg,
+        verify_signatures,
+    )
+    .await?;
+
+    let StreamingCallbackHttpResponse { body, token } =
+        candid::decode_one(&reply).map_err(|e| AgentError::CandidError(Box::new(e)))?;
+
+    Ok((body, token))
+}
+
+/// Makes a query call and returns the reply along with whether the signatures of the replica
+/// nodes on it were verified.
+///
+/// If `verify_signatures` is set, the signatures are verified regardless of whether the agent
+/// is configured to verify query signatures; the agent fetches the public keys of the subnet's
+/// nodes using `read_state` and caches them. Otherwise the agent's own configuration applies,
+/// and the signatures are not reported as verified since the agent does not expose it.
+async fn query(
+    agent: &Agent,
+    canister_id: Principal,
+    method_name: &str,
+    arg: Vec<u8>,
+    verify_signatures: bool,
+) -> Result<(Vec<u8>, bool), AgentError> {
+    let query = agent.query(&canister_id, method_name).with_arg(arg);
+
+    if verify_signatures {
+        Ok((query.call_with_verification().await?, true))
+    } else {
+        Ok((query.call().await?, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Deserialize;
+
+    /// The `http_request` argument as declared by canisters.
+    #[derive(CandidType, Deserialize, Debug, PartialEq)]
+    struct CanisterHttpRequest {
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        certificate_version: Option<u16>,
+    }
+
+    #[test]
+    fn test_http_request_arg_matches_canister_interface() {
+        let arg = candid::encode_one(HttpRequestArg {
+            method: "GET",
+            url: "/index.html",
+            headers: vec![HeaderField("accept".into(), "text/html".into())],
+            body: b"body",
+            certificate_version: Some(&2),
+        })
+        .unwrap();
+
+        assert_eq!(
+            candid::decode_one::<CanisterHttpRequest>(&arg).unwrap(),
+            CanisterHttpRequest {
+                method: "GET".to_string(),
+                url: "/index.html".to_string(),
+                headers: vec![("accept".to_string(), "text/html".to_string())],
+                body: b"body".to_vec(),
+                certificate_version: Some(2),
+            }
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use crate::AgentResponseAny;
+use candid::Principal;
+use ic_agent::{Agent, AgentError, RequestId};
+use ic_utils::call::AsyncCall;
+
+/// Submits an update call and waits for its certified reply, see [wait_certified].
+pub async fn call_and_wait_certified<C>(
+    agent: &Agent,
+    canister_id: Principal,
+    update_call: C,
+) -> Result<AgentResponseAny, AgentError>
+where
+    C: AsyncCall<(AgentResponseAny,)>,
+{
+    let request_id = update_call.call().await?;
+
+    wait_certified(agent, request_id, canister_id).await
+}
+
+/// Waits for the certified reply of a submitted update call.
+///
+/// The agent polls the status of the call with `read_state`, verifies each returned certificate
+/// against the root key, checks that the subnet that signed it is authorized for `canister_id`,
+/// and only returns the reply found under `request_status/<request_id>/reply` of the certified
+/// state tree once the status is `replied`.
+pub async fn wait_certified(
+    agent: &Agent,
+    request_id: RequestId,
+    canister_id: Principal,
+) -> Result<AgentResponseAny, AgentError> {
+    let reply = agent.wait(request_id, canister_id).await?;
+
+    decode_update_reply(&reply)
+}
+
+pub fn decode_update_reply(reply: &[u8]) -> Result<AgentResponseAny, AgentError> {
+    candid::decode_one(reply).map_err(|e| AgentError::CandidError(Box::new(e)))
+}
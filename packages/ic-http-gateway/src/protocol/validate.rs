@@ -1,4 +1,7 @@
-use crate::HttpGatewayResult;
+use crate::{
+    HttpGatewayError, HttpGatewayResult, CERTIFICATE_EXPRESSION_HEADER_NAME,
+    SKIP_CERTIFICATION_FIELD_NAME,
+};
 use candid::Principal;
 use ic_agent::Agent;
 use ic_http_certification::{HttpRequest, HttpResponse};
@@ -9,31 +12,77 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 const MAX_CERT_TIME_OFFSET_NS: u128 = 300_000_000_000;
 
+/// Verifies `response` and returns the verification info, along with the body of the response.
+///
+/// Verification takes ownership of the body, and only hands it back as part of the verified
+/// response. Responses that certifiably skip certification are therefore verified without their
+/// body, which is not certified anyway, so that the body is not copied. The body is only copied
+/// for v1 responses, which are verified without a certificate expression.
 pub fn validate(
     agent: &Agent,
     canister_id: &Principal,
     request: HttpRequest,
-    response: HttpResponse,
+    mut response: HttpResponse,
     skip_verification: bool,
-) -> HttpGatewayResult<Option<VerificationInfo>> {
+) -> HttpGatewayResult<(Option<VerificationInfo>, Vec<u8>)> {
     if skip_verification {
         // TODO: Remove this (FOLLOW-483)
         // Canisters don't have to provide certified variables
         // This should change in the future, grandfathering in current implementations
-        return Ok(None);
+        return Ok((None, response.body));
     }
 
     let ic_public_key = agent.read_root_key();
-    let verification_info = verify_request_response_pair(
-        request,
-        response,
-        canister_id.as_slice(),
-        get_current_time_in_ns(),
-        MAX_CERT_TIME_OFFSET_NS,
-        ic_public_key.as_slice(),
-        MIN_VERIFICATION_VERSION,
-    )?;
-    Ok(Some(verification_info))
+    let verify = |request: HttpRequest, response: HttpResponse| {
+        verify_request_response_pair(
+            request,
+            response,
+            canister_id.as_slice(),
+            get_current_time_in_ns(),
+            MAX_CERT_TIME_OFFSET_NS,
+            ic_public_key.as_slice(),
+            MIN_VERIFICATION_VERSION,
+        )
+    };
+
+    let Some(certificate_expression) = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(CERTIFICATE_EXPRESSION_HEADER_NAME))
+        .map(|(_, value)| value.clone())
+    else {
+        let body = response.body.clone();
+        let verification_info = verify(request, response)?;
+
+        return Ok((Some(verification_info), body));
+    };
+
+    if certificate_expression.contains(SKIP_CERTIFICATION_FIELD_NAME) {
+        let body = std::mem::take(&mut response.body);
+
+        // if the response does not actually skip certification, it is verified with its body below
+        match verify(request.clone(), response.clone()) {
+            Ok(verification_info) if verification_info.response.is_none() => {
+                return Ok((Some(verification_info), body));
+            }
+            _ => response.body = body,
+        }
+    }
+
+    let mut verification_info = verify(request, response)?;
+    // responses are only verified without a verified response if they skip certification,
+    // and certificate expressions that skip certification always name the skipping field
+    let body = verification_info
+        .response
+        .as_mut()
+        .map(|verified_response| std::mem::take(&mut verified_response.body))
+        .ok_or_else(|| {
+            HttpGatewayError::HttpError(
+                "Response verification did not return the verified body".to_string(),
+            )
+        })?;
+
+    Ok((Some(verification_info), body))
 }
 
 fn get_current_time_in_ns() -> u128 {
@@ -44,3 +93,124 @@ fn get_current_time_in_ns() -> u128 {
         .expect("Time went backwards")
         .as_nanos()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{CertificationFixture, FakeReplica, FixtureCertification, TestRootKey};
+    use ic_http_certification::{DefaultCelBuilder, DefaultResponseCertification};
+
+    fn canister_id() -> Principal {
+        Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+    }
+
+    fn request() -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: "/index.html?lang=en".to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn response() -> HttpResponse {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![("content-type".to_string(), "text/html".to_string())],
+            body: b"<html></html>".to_vec(),
+            upgrade: None,
+        }
+    }
+
+    fn certify(certification: FixtureCertification) -> HttpResponse {
+        CertificationFixture::new(TestRootKey::default(), canister_id()).certify(
+            &request(),
+            response(),
+            certification,
+        )
+    }
+
+    fn agent() -> Agent {
+        FakeReplica::new(TestRootKey::default()).agent()
+    }
+
+    #[test]
+    fn test_validate_v1() {
+        let response = certify(FixtureCertification::V1);
+
+        let (verification_info, body) =
+            validate(&agent(), &canister_id(), request(), response, false).unwrap();
+        let verification_info = verification_info.unwrap();
+
+        assert_eq!(body, b"<html></html>");
+
+        assert_eq!(verification_info.verification_version, 1);
+    }
+
+    #[test]
+    fn test_validate_v2_response_only() {
+        let cel_expr = DefaultCelBuilder::response_only_certification()
+            .with_response_certification(DefaultResponseCertification::response_header_exclusions(
+                vec![],
+            ))
+            .build();
+        let response = certify(FixtureCertification::ResponseOnly(cel_expr));
+
+        let (verification_info, body) =
+            validate(&agent(), &canister_id(), request(), response, false).unwrap();
+        let verification_info = verification_info.unwrap();
+
+        assert_eq!(body, b"<html></html>");
+
+        assert_eq!(verification_info.verification_version, 2);
+        assert!(verification_info.response.is_some());
+    }
+
+    #[test]
+    fn test_validate_v2_skip() {
+        let response = certify(FixtureCertification::Skip);
+
+        let (verification_info, body) =
+            validate(&agent(), &canister_id(), request(), response, false).unwrap();
+        let verification_info = verification_info.unwrap();
+
+        assert_eq!(body, b"<html></html>");
+
+        assert_eq!(verification_info.verification_version, 2);
+        assert!(verification_info.response.is_none());
+    }
+
+    #[test]
+    fn test_validate_rejects_tampered_body() {
+        let mut response = certify(FixtureCertification::V1);
+        response.body = b"<html>tampered</html>".to_vec();
+
+        assert!(validate(&agent(), &canister_id(), request(), response, false).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_other_root_keys() {
+        let response = CertificationFixture::new(TestRootKey::from_seed(b"other"), canister_id())
+            .certify(&request(), response(), FixtureCertification::V1);
+
+        assert!(validate(&agent(), &canister_id(), request(), response, false).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_outdated_certificates() {
+        let response = CertificationFixture::new(TestRootKey::default(), canister_id())
+            .with_time(0)
+            .certify(&request(), response(), FixtureCertification::V1);
+
+        assert!(validate(&agent(), &canister_id(), request(), response, false).is_err());
+    }
+
+    #[test]
+    fn test_validate_skip_verification() {
+        let (verification_info, body) =
+            validate(&agent(), &canister_id(), request(), response(), true).unwrap();
+
+        assert!(verification_info.is_none());
+        assert_eq!(body, b"<html></html>");
+    }
+}
@@ -0,0 +1,258 @@
+use candid::Principal;
+use ic_agent::RequestId;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_STATUS_PATH_PREFIX: &str = "/_/http-gateway/update-calls/";
+const DEFAULT_MAX_IN_FLIGHT: usize = 1_000;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+// the replica keeps the status of a request for at least the maximum ingress expiry (5 minutes)
+const DEFAULT_ENTRY_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Enables the asynchronous update call mode.
+///
+/// In this mode, requests that are upgraded to update calls are submitted to the canister
+/// and answered immediately with `202 Accepted`. The `Location` header of that response
+/// points to a status URL on the same canister that can be polled for the result of the call.
+/// Polling the status URL returns `202 Accepted` while the call is in progress and the certified
+/// response of the canister (or the reject) once it is available.
+///
+/// In-flight calls are kept in a bounded store that is shared by all clones of this value.
+/// If the store is full, update calls are answered synchronously.
+#[derive(Debug, Clone)]
+pub struct AsyncUpdateCalls {
+    status_path_prefix: String,
+    max_in_flight: usize,
+    retry_after: Duration,
+    entry_ttl: Duration,
+    store: Arc<Mutex<AsyncUpdateCallStore>>,
+}
+
+#[derive(Debug, Default)]
+struct AsyncUpdateCallStore {
+    entries: HashMap<String, AsyncUpdateCallEntry>,
+    order: VecDeque<String>,
+}
+
+#[derive(Debug)]
+struct AsyncUpdateCallEntry {
+    canister_id: Principal,
+    request_id: RequestId,
+    created_at: Instant,
+}
+
+impl AsyncUpdateCalls {
+    pub fn new() -> Self {
+        Self {
+            status_path_prefix: DEFAULT_STATUS_PATH_PREFIX.to_string(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            retry_after: DEFAULT_RETRY_AFTER,
+            entry_ttl: DEFAULT_ENTRY_TTL,
+            store: Default::default(),
+        }
+    }
+
+    /// Sets the path prefix of status URLs. Requests whose path starts with this prefix
+    /// are handled by the gateway and never forwarded to the canister.
+    pub fn with_status_path_prefix(mut self, status_path_prefix: impl Into<String>) -> Self {
+        let mut status_path_prefix = status_path_prefix.into();
+        if !status_path_prefix.ends_with('/') {
+            status_path_prefix.push('/');
+        }
+        self.status_path_prefix = status_path_prefix;
+
+        self
+    }
+
+    /// Sets the maximum number of update calls that can be in flight at the same time.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+
+        self
+    }
+
+    /// Sets the value of the `Retry-After` header returned while an update call is in progress.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+
+        self
+    }
+
+    /// Sets how long the status of an update call can be polled after it was submitted.
+    pub fn with_entry_ttl(mut self, entry_ttl: Duration) -> Self {
+        self.entry_ttl = entry_ttl;
+
+        self
+    }
+
+    pub(crate) fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+
+    pub(crate) fn status_url(&self, token: &str) -> String {
+        format!("{}{}", self.status_path_prefix, token)
+    }
+
+    /// Returns the token of the status URL if the given URL is one.
+    pub(crate) fn parse_status_url<'a>(&self, url: &'a str) -> Option<&'a str> {
+        let path = url.split('?').next().unwrap_or_default();
+        let token = path.strip_prefix(&self.status_path_prefix)?;
+
+        Some(token)
+    }
+
+    /// Stores an in-flight update call and returns the token of its status URL,
+    /// or `None` if the maximum number of in-flight update calls has been reached.
+    pub(crate) fn insert(&self, canister_id: Principal, request_id: RequestId) -> Option<String> {
+        let mut store = self.store.lock().unwrap();
+        store.remove_expired(self.entry_ttl);
+
+        if store.entries.len() >= self.max_in_flight {
+            return None;
+        }
+
+        let token = request_id
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if store.entries.contains_key(&token) {
+            return Some(token);
+        }
+
+        store.entries.insert(
+            token.clone(),
+            AsyncUpdateCallEntry {
+                canister_id,
+                request_id,
+                created_at: Instant::now(),
+            },
+        );
+        store.order.push_back(token.clone());
+
+        Some(token)
+    }
+
+    /// Returns the request id of an in-flight update call made to the given canister.
+    pub(crate) fn get(&self, canister_id: &Principal, token: &str) -> Option<RequestId> {
+        let mut store = self.store.lock().unwrap();
+        store.remove_expired(self.entry_ttl);
+
+        store
+            .entries
+            .get(token)
+            .filter(|entry| entry.canister_id == *canister_id)
+            .map(|entry| entry.request_id)
+    }
+
+    pub(crate) fn remove(&self, token: &str) {
+        let mut store = self.store.lock().unwrap();
+
+        if store.entries.remove(token).is_some() {
+            store.order.retain(|t| t != token);
+        }
+    }
+}
+
+impl Default for AsyncUpdateCalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncUpdateCallStore {
+    fn remove_expired(&mut self, entry_ttl: Duration) {
+        while let Some(token) = self.order.front() {
+            let is_expired = self
+                .entries
+                .get(token)
+                .map_or(true, |entry| entry.created_at.elapsed() >= entry_ttl);
+            if !is_expired {
+                break;
+            }
+
+            if let Some(token) = self.order.pop_front() {
+                self.entries.remove(&token);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+    }
+
+    fn request_id(byte: u8) -> RequestId {
+        RequestId::new(&[byte; 32])
+    }
+
+    #[test]
+    fn test_status_url() {
+        let async_update_calls = AsyncUpdateCalls::new().with_status_path_prefix("/_/calls");
+
+        assert_eq!(async_update_calls.status_url("ab"), "/_/calls/ab");
+        assert_eq!(
+            async_update_calls.parse_status_url("/_/calls/ab?x=1"),
+            Some("ab")
+        );
+        assert_eq!(async_update_calls.parse_status_url("/_/callsab"), None);
+        assert_eq!(async_update_calls.parse_status_url("/index.html"), None);
+    }
+
+    #[test]
+    fn test_store_is_bounded() {
+        let async_update_calls = AsyncUpdateCalls::new().with_max_in_flight(1);
+
+        let token = async_update_calls
+            .insert(canister_id(), request_id(1))
+            .unwrap();
+        assert_eq!(token, "01".repeat(32));
+        assert_eq!(
+            async_update_calls.insert(canister_id(), request_id(2)),
+            None
+        );
+
+        async_update_calls.remove(&token);
+        assert!(async_update_calls
+            .insert(canister_id(), request_id(2))
+            .is_some());
+    }
+
+    #[test]
+    fn test_store_is_scoped_to_canister() {
+        let async_update_calls = AsyncUpdateCalls::new();
+        let token = async_update_calls
+            .insert(canister_id(), request_id(1))
+            .unwrap();
+
+        assert_eq!(
+            async_update_calls.get(&canister_id(), &token),
+            Some(request_id(1))
+        );
+        assert_eq!(
+            async_update_calls.get(&Principal::anonymous(), &token),
+            None
+        );
+    }
+
+    #[test]
+    fn test_store_expires_entries() {
+        let async_update_calls = AsyncUpdateCalls::new()
+            .with_max_in_flight(1)
+            .with_entry_ttl(Duration::ZERO);
+        let token = async_update_calls
+            .insert(canister_id(), request_id(1))
+            .unwrap();
+
+        assert_eq!(async_update_calls.get(&canister_id(), &token), None);
+        assert!(async_update_calls
+            .insert(canister_id(), request_id(2))
+            .is_some());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::UpgradeMatcher;
+use candid::Principal;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+type RouteKey = (Principal, String, String);
+
+/// Lets the gateway call `http_request_update` directly, skipping the query call,
+/// for requests that are known to be upgraded to update calls.
+///
+/// Requests are called directly if they match one of the configured rules of their canister,
+/// or, if the adaptive cache is enabled, if a request with the same method and URL to the
+/// same canister was recently upgraded. The adaptive cache includes the query string in the URL,
+/// because canisters may decide whether to upgrade a request based on its query parameters.
+/// Direct update calls are still subject to the [UpgradePolicy](crate::UpgradePolicy) of the client,
+/// and only upgrades that were allowed by the policy are remembered.
+#[derive(Debug, Clone, Default)]
+pub struct DirectUpdateCalls {
+    canister_rules: HashMap<Principal, Vec<UpgradeMatcher>>,
+    adaptive_cache: Option<(usize, Duration)>,
+    upgraded_routes: Arc<Mutex<UpgradedRoutes>>,
+}
+
+#[derive(Debug, Default)]
+struct UpgradedRoutes {
+    entries: HashMap<RouteKey, Instant>,
+    order: VecDeque<RouteKey>,
+}
+
+impl DirectUpdateCalls {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Calls requests to the given canister that match `matcher` directly as update calls.
+    pub fn with_rule(mut self, canister_id: Principal, matcher: UpgradeMatcher) -> Self {
+        self.canister_rules
+            .entry(canister_id)
+            .or_default()
+            .push(matcher);
+
+        self
+    }
+
+    /// Remembers up to `max_entries` URLs that were upgraded to update calls for `ttl`,
+    /// and calls requests to these URLs directly as update calls.
+    /// The cache is shared by all clones of this value.
+    pub fn with_adaptive_cache(mut self, max_entries: usize, ttl: Duration) -> Self {
+        self.adaptive_cache = Some((max_entries, ttl));
+
+        self
+    }
+
+    pub(crate) fn should_call_directly(
+        &self,
+        canister_id: &Principal,
+        method: &str,
+        url: &str,
+    ) -> bool {
+        let path = url.split('?').next().unwrap_or_default();
+
+        let matches_rule = self
+            .canister_rules
+            .get(canister_id)
+            .is_some_and(|matchers| matchers.iter().any(|m| m.matches(method, path)));
+        if matches_rule {
+            return true;
+        }
+
+        let Some((_, ttl)) = self.adaptive_cache else {
+            return false;
+        };
+
+        let mut upgraded_routes = self.upgraded_routes.lock().unwrap();
+        upgraded_routes.remove_expired(ttl);
+
+        upgraded_routes
+            .entries
+            .contains_key(&route_key(canister_id, method, url))
+    }
+
+    /// Records that a request was upgraded to an update call by the canister.
+    pub(crate) fn record_upgrade(&self, canister_id: &Principal, method: &str, url: &str) {
+        let Some((max_entries, ttl)) = self.adaptive_cache else {
+            return;
+        };
+        if max_entries == 0 {
+            return;
+        }
+
+        let key = route_key(canister_id, method, url);
+
+        let mut upgraded_routes = self.upgraded_routes.lock().unwrap();
+        upgraded_routes.remove_expired(ttl);
+
+        if upgraded_routes.entries.remove(&key).is_some() {
+            upgraded_routes.order.retain(|k| *k != key);
+        }
+        while upgraded_routes.entries.len() >= max_entries {
+            match upgraded_routes.order.pop_front() {
+                Some(oldest) => {
+                    upgraded_routes.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        upgraded_routes.entries.insert(key.clone(), Instant::now());
+        upgraded_routes.order.push_back(key);
+    }
+}
+
+impl UpgradedRoutes {
+    fn remove_expired(&mut self, ttl: Duration) {
+        while let Some(key) = self.order.front() {
+            let is_expired = self
+                .entries
+                .get(key)
+                .map_or(true, |recorded_at| recorded_at.elapsed() >= ttl);
+            if !is_expired {
+                break;
+            }
+
+            if let Some(key) = self.order.pop_front() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+fn route_key(canister_id: &Principal, method: &str, url: &str) -> RouteKey {
+    (*canister_id, method.to_ascii_uppercase(), url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+    }
+
+    #[test]
+    fn test_direct_update_rules() {
+        let direct_update_calls = DirectUpdateCalls::new()
+            .with_rule(canister_id(), UpgradeMatcher::new().with_method("POST"));
+
+        assert!(direct_update_calls.should_call_directly(&canister_id(), "POST", "/api?a=1"));
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "GET", "/api"));
+        assert!(!direct_update_calls.should_call_directly(&Principal::anonymous(), "POST", "/api"));
+    }
+
+    #[test]
+    fn test_adaptive_cache() {
+        let direct_update_calls =
+            DirectUpdateCalls::new().with_adaptive_cache(1, Duration::from_secs(60));
+
+        direct_update_calls.record_upgrade(&canister_id(), "get", "/counter?x=1");
+        assert!(direct_update_calls.should_call_directly(&canister_id(), "GET", "/counter?x=1"));
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "GET", "/counter"));
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "POST", "/counter?x=1"));
+
+        // the oldest route is evicted when the cache is full
+        direct_update_calls.record_upgrade(&canister_id(), "GET", "/other");
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "GET", "/counter?x=1"));
+        assert!(direct_update_calls
+            .clone()
+            .should_call_directly(&canister_id(), "GET", "/other"));
+    }
+
+    #[test]
+    fn test_adaptive_cache_expires_routes() {
+        let direct_update_calls = DirectUpdateCalls::new().with_adaptive_cache(10, Duration::ZERO);
+
+        direct_update_calls.record_upgrade(&canister_id(), "GET", "/counter");
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "GET", "/counter"));
+    }
+
+    #[test]
+    fn test_adaptive_cache_disabled() {
+        let direct_update_calls = DirectUpdateCalls::new();
+
+        direct_update_calls.record_upgrade(&canister_id(), "GET", "/counter");
+        assert!(!direct_update_calls.should_call_directly(&canister_id(), "GET", "/counter"));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{
+    FORWARDED_HEADER_NAME, X_FORWARDED_FOR_HEADER_NAME, X_FORWARDED_PROTO_HEADER_NAME,
+    X_REAL_IP_HEADER_NAME,
+};
+use std::net::IpAddr;
+
+/// Information about the client connection that cannot be derived from the request itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The IP address of the peer that connected to the gateway.
+    pub ip: IpAddr,
+
+    /// The scheme that the peer used to connect to the gateway, e.g. `https`.
+    pub scheme: String,
+
+    /// The host that the peer requested, e.g. the TLS SNI or the `Host` header.
+    pub host: Option<String>,
+}
+
+/// Configures the injection of `Forwarded`, `X-Forwarded-For`, `X-Forwarded-Proto`
+/// and `X-Real-IP` headers into requests made to canisters.
+///
+/// If the peer is a trusted proxy, forwarding headers that are already present on the
+/// request are preserved and the peer's information is appended to them. Otherwise,
+/// any forwarding headers sent by the peer are replaced.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardingHeaders {
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl ForwardingHeaders {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Trusts forwarding headers that are sent by a peer with the given IP address.
+    pub fn with_trusted_proxy(mut self, ip: IpAddr) -> Self {
+        self.trusted_proxies.push(ip);
+
+        self
+    }
+
+    pub(crate) fn apply(&self, headers: &mut Vec<(String, String)>, client_info: &ClientInfo) {
+        let is_trusted_proxy = self.trusted_proxies.contains(&client_info.ip);
+        let client_ip = client_info.ip.to_string();
+
+        let mut forwarded_element = format!("for={}", format_forwarded_node(&client_info.ip));
+        forwarded_element.push_str(&format!(
+            ";proto={}",
+            format_forwarded_value(&client_info.scheme)
+        ));
+        if let Some(host) = &client_info.host {
+            forwarded_element.push_str(&format!(";host={}", format_forwarded_value(host)));
+        }
+
+        if is_trusted_proxy {
+            append_header(headers, FORWARDED_HEADER_NAME, &forwarded_element);
+            append_header(headers, X_FORWARDED_FOR_HEADER_NAME, &client_ip);
+            set_header_if_missing(headers, X_FORWARDED_PROTO_HEADER_NAME, &client_info.scheme);
+            set_header_if_missing(headers, X_REAL_IP_HEADER_NAME, &client_ip);
+        } else {
+            headers.retain(|(name, _)| {
+                ![
+                    FORWARDED_HEADER_NAME,
+                    X_FORWARDED_FOR_HEADER_NAME,
+                    X_FORWARDED_PROTO_HEADER_NAME,
+                    X_REAL_IP_HEADER_NAME,
+                ]
+                .iter()
+                .any(|forwarding_header| name.eq_ignore_ascii_case(forwarding_header))
+            });
+
+            headers.push((FORWARDED_HEADER_NAME.to_string(), forwarded_element));
+            headers.push((X_FORWARDED_FOR_HEADER_NAME.to_string(), client_ip.clone()));
+            headers.push((
+                X_FORWARDED_PROTO_HEADER_NAME.to_string(),
+                client_info.scheme.clone(),
+            ));
+            headers.push((X_REAL_IP_HEADER_NAME.to_string(), client_ip));
+        }
+    }
+}
+
+/// Merges all existing values of the given header into a single header and appends `value` to it.
+fn append_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    let mut values = vec![];
+    headers.retain(|(header_name, header_value)| {
+        if header_name.eq_ignore_ascii_case(name) {
+            values.push(header_value.clone());
+            return false;
+        }
+
+        true
+    });
+    values.push(value.to_string());
+
+    headers.push((name.to_string(), values.join(", ")));
+}
+
+fn set_header_if_missing(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    if !headers
+        .iter()
+        .any(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+    {
+        headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+/// Formats a node identifier as described in RFC 7239, section 6.
+fn format_forwarded_node(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!(r#""[{}]""#, ip),
+    }
+}
+
+/// Quotes a `Forwarded` parameter value if it is not a valid token, see RFC 7239, section 4.
+fn format_forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!(r#""{}""#, value.replace('\\', r"\\").replace('"', r#"\""#))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn client_info(ip: IpAddr) -> ClientInfo {
+        ClientInfo {
+            ip,
+            scheme: "https".to_string(),
+            host: Some("example.com:8443".to_string()),
+        }
+    }
+
+    fn existing_headers() -> Vec<(String, String)> {
+        vec![
+            ("accept".to_string(), "text/html".to_string()),
+            ("forwarded".to_string(), "for=198.51.100.17".to_string()),
+            ("x-forwarded-for".to_string(), "198.51.100.17".to_string()),
+            ("x-forwarded-proto".to_string(), "http".to_string()),
+            ("x-real-ip".to_string(), "198.51.100.17".to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_apply_replaces_headers_from_untrusted_peer() {
+        let mut headers = existing_headers();
+
+        ForwardingHeaders::new().apply(
+            &mut headers,
+            &client_info(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5))),
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                (
+                    "forwarded".to_string(),
+                    r#"for=203.0.113.5;proto=https;host="example.com:8443""#.to_string()
+                ),
+                ("x-forwarded-for".to_string(), "203.0.113.5".to_string()),
+                ("x-forwarded-proto".to_string(), "https".to_string()),
+                ("x-real-ip".to_string(), "203.0.113.5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_appends_headers_from_trusted_proxy() {
+        let proxy_ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let mut headers = existing_headers();
+
+        ForwardingHeaders::new()
+            .with_trusted_proxy(proxy_ip)
+            .apply(&mut headers, &client_info(proxy_ip));
+
+        assert_eq!(
+            headers,
+            vec![
+                ("accept".to_string(), "text/html".to_string()),
+                ("x-forwarded-proto".to_string(), "http".to_string()),
+                ("x-real-ip".to_string(), "198.51.100.17".to_string()),
+                (
+                    "forwarded".to_string(),
+                    r#"for=198.51.100.17, for="[::1]";proto=https;host="example.com:8443""#
+                        .to_string()
+                ),
+                (
+                    "x-forwarded-for".to_string(),
+                    "198.51.100.17, ::1".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use http::HeaderValue;
+
+/// What to do with a header value that cannot be represented as-is.
+///
+/// For requests, this applies to header values that are not visible ASCII, such as
+/// Latin-1 encoded filenames sent by some browsers. For responses, this applies to
+/// header values returned by the canister that are not valid HTTP header values,
+/// such as values containing control characters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidHeaderValueAction {
+    /// Fail the request with an error response.
+    #[default]
+    Reject,
+
+    /// Remove the offending header and continue.
+    Drop,
+
+    /// Lossily convert the offending header value and continue.
+    /// Request header values are decoded as UTF-8 if possible and as Latin-1 otherwise.
+    /// Control characters are removed from response header values.
+    Transcode,
+}
+
+/// Whether a header was sent by the client or returned by the canister.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderDirection {
+    Request,
+    Response,
+}
+
+/// A header value that could not be represented as-is, and the action that was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidHeaderValue {
+    /// The name of the offending header.
+    pub header_name: String,
+
+    /// Whether the header was sent by the client or returned by the canister.
+    pub direction: HeaderDirection,
+
+    /// The action that was taken.
+    pub action: InvalidHeaderValueAction,
+}
+
+pub(crate) fn convert_request_header_value(
+    header_name: &str,
+    header_value: &HeaderValue,
+    action: InvalidHeaderValueAction,
+    invalid_header_values: &mut Vec<InvalidHeaderValue>,
+) -> HttpGatewayResult<Option<String>> {
+    if let Ok(header_value) = header_value.to_str() {
+        return Ok(Some(header_value.to_string()));
+    }
+
+    let header_value = match action {
+        InvalidHeaderValueAction::Reject => {
+            return Err(HttpGatewayError::HeaderValueParsingError {
+                header_name: header_name.to_string(),
+                header_value: String::from_utf8_lossy(header_value.as_bytes()).to_string(),
+            });
+        }
+        InvalidHeaderValueAction::Drop => None,
+        InvalidHeaderValueAction::Transcode => {
+            Some(match std::str::from_utf8(header_value.as_bytes()) {
+                Ok(header_value) => header_value.to_string(),
+                Err(_) => header_value
+                    .as_bytes()
+                    .iter()
+                    .map(|b| char::from(*b))
+                    .collect(),
+            })
+        }
+    };
+
+    invalid_header_values.push(InvalidHeaderValue {
+        header_name: header_name.to_string(),
+        direction: HeaderDirection::Request,
+        action,
+    });
+
+    Ok(header_value)
+}
+
+pub(crate) fn convert_response_header_value(
+    header_name: &str,
+    header_value: &str,
+    action: InvalidHeaderValueAction,
+    invalid_header_values: &mut Vec<InvalidHeaderValue>,
+) -> HttpGatewayResult<Option<HeaderValue>> {
+    if let Ok(header_value) = HeaderValue::from_str(header_value) {
+        return Ok(Some(header_value));
+    }
+
+    let (header_value, action) = match action {
+        InvalidHeaderValueAction::Reject => {
+            return Err(HttpGatewayError::HeaderValueParsingError {
+                header_name: header_name.to_string(),
+                header_value: header_value.to_string(),
+            });
+        }
+        InvalidHeaderValueAction::Drop => (None, action),
+        InvalidHeaderValueAction::Transcode => {
+            let header_value = header_value
+                .chars()
+                .filter(|c| !c.is_control() || *c == '\t')
+                .collect::<String>();
+
+            // the header is dropped if the transcoded value is still not a valid header value
+            match HeaderValue::from_str(&header_value) {
+                Ok(header_value) => (Some(header_value), action),
+                Err(_) => (None, InvalidHeaderValueAction::Drop),
+            }
+        }
+    };
+
+    invalid_header_values.push(InvalidHeaderValue {
+        header_name: header_name.to_string(),
+        direction: HeaderDirection::Response,
+        action,
+    });
+
+    Ok(header_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_convert_request_header_value() {
+        let latin1_value =
+            HeaderValue::from_bytes(b"attachment; filename=\"r\xe9sum\xe9.pdf\"").unwrap();
+        let utf8_value = HeaderValue::from_bytes("filename=\"résumé.pdf\"".as_bytes()).unwrap();
+        let mut invalid_header_values = vec![];
+
+        assert!(matches!(
+            convert_request_header_value(
+                "content-disposition",
+                &latin1_value,
+                InvalidHeaderValueAction::Reject,
+                &mut invalid_header_values,
+            ),
+            Err(HttpGatewayError::HeaderValueParsingError { .. })
+        ));
+        assert_eq!(
+            convert_request_header_value(
+                "content-disposition",
+                &latin1_value,
+                InvalidHeaderValueAction::Drop,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            convert_request_header_value(
+                "content-disposition",
+                &latin1_value,
+                InvalidHeaderValueAction::Transcode,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            Some("attachment; filename=\"résumé.pdf\"".to_string())
+        );
+        assert_eq!(
+            convert_request_header_value(
+                "x-filename",
+                &utf8_value,
+                InvalidHeaderValueAction::Transcode,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            Some("filename=\"résumé.pdf\"".to_string())
+        );
+
+        assert_eq!(
+            invalid_header_values,
+            vec![
+                InvalidHeaderValue {
+                    header_name: "content-disposition".to_string(),
+                    direction: HeaderDirection::Request,
+                    action: InvalidHeaderValueAction::Drop,
+                },
+                InvalidHeaderValue {
+                    header_name: "content-disposition".to_string(),
+                    direction: HeaderDirection::Request,
+                    action: InvalidHeaderValueAction::Transcode,
+                },
+                InvalidHeaderValue {
+                    header_name: "x-filename".to_string(),
+                    direction: HeaderDirection::Request,
+                    action: InvalidHeaderValueAction::Transcode,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_response_header_value() {
+        let mut invalid_header_values = vec![];
+
+        assert_eq!(
+            convert_response_header_value(
+                "content-disposition",
+                "filename=\"résumé.pdf\"",
+                InvalidHeaderValueAction::Reject,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            Some(HeaderValue::from_bytes("filename=\"résumé.pdf\"".as_bytes()).unwrap())
+        );
+        assert!(matches!(
+            convert_response_header_value(
+                "x-injected",
+                "a\r\nset-cookie: b",
+                InvalidHeaderValueAction::Reject,
+                &mut invalid_header_values,
+            ),
+            Err(HttpGatewayError::HeaderValueParsingError { .. })
+        ));
+        assert_eq!(
+            convert_response_header_value(
+                "x-injected",
+                "a\r\nset-cookie: b",
+                InvalidHeaderValueAction::Drop,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            convert_response_header_value(
+                "x-injected",
+                "a\r\nset-cookie: b",
+                InvalidHeaderValueAction::Transcode,
+                &mut invalid_header_values,
+            )
+            .unwrap(),
+            Some(HeaderValue::from_static("aset-cookie: b"))
+        );
+
+        assert_eq!(
+            invalid_header_values,
+            vec![
+                InvalidHeaderValue {
+                    header_name: "x-injected".to_string(),
+                    direction: HeaderDirection::Response,
+                    action: InvalidHeaderValueAction::Drop,
+                },
+                InvalidHeaderValue {
+                    header_name: "x-injected".to_string(),
+                    direction: HeaderDirection::Response,
+                    action: InvalidHeaderValueAction::Transcode,
+                },
+            ]
+        );
+    }
+
+    /// Returns the bytes of a valid header value, which may contain any byte but controls other than tab.
+    fn header_value_bytes() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec(prop_oneof![Just(b'\t'), 0x20u8..0x7f, 0x80u8..=0xff], 0..64)
+    }
+
+    fn header_value_action() -> impl Strategy<Value = InvalidHeaderValueAction> {
+        prop_oneof![
+            Just(InvalidHeaderValueAction::Reject),
+            Just(InvalidHeaderValueAction::Drop),
+            Just(InvalidHeaderValueAction::Transcode),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_convert_request_header_value(
+            bytes in header_value_bytes(),
+            action in header_value_action(),
+        ) {
+            let header_value = HeaderValue::from_bytes(&bytes).unwrap();
+            let mut invalid_header_values = vec![];
+
+            let result = convert_request_header_value(
+                "x-header",
+                &header_value,
+                action,
+                &mut invalid_header_values,
+            );
+
+            match (header_value.to_str(), action, result) {
+                // visible ASCII values are always passed through as-is
+                (Ok(value), _, Ok(converted)) => {
+                    prop_assert_eq!(converted.as_deref(), Some(value));
+                    prop_assert!(invalid_header_values.is_empty());
+                }
+                (Err(_), InvalidHeaderValueAction::Reject, Err(_)) => {
+                    prop_assert!(invalid_header_values.is_empty());
+                }
+                (Err(_), InvalidHeaderValueAction::Drop, Ok(None)) => {
+                    prop_assert_eq!(invalid_header_values.len(), 1);
+                }
+                (Err(_), InvalidHeaderValueAction::Transcode, Ok(Some(converted))) => {
+                    // every byte is decoded into exactly one character, unless the value is UTF-8
+                    match std::str::from_utf8(&bytes) {
+                        Ok(value) => prop_assert_eq!(converted, value),
+                        Err(_) => prop_assert_eq!(converted.chars().count(), bytes.len()),
+                    }
+                    prop_assert_eq!(invalid_header_values.len(), 1);
+                }
+                (_, action, result) => {
+                    prop_assert!(false, "unexpected result {:?} for action {:?}", result, action);
+                }
+            }
+        }
+    }
+}
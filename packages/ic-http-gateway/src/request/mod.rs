@@ -1,2 +1,23 @@
 mod http_gateway_request_builder;
 pub use http_gateway_request_builder::*;
+
+mod forwarding_headers;
+pub use forwarding_headers::*;
+
+mod request_header_policy;
+pub use request_header_policy::*;
+
+mod url_normalization;
+pub use url_normalization::*;
+
+mod invalid_header_value;
+pub use invalid_header_value::*;
+
+mod async_update_calls;
+pub use async_update_calls::*;
+
+mod upgrade_policy;
+pub use upgrade_policy::*;
+
+mod direct_update_calls;
+pub use direct_update_calls::*;
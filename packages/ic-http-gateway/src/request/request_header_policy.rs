@@ -0,0 +1,121 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use std::collections::HashSet;
+
+/// Controls which request headers are forwarded to canisters.
+///
+/// The policy is applied in the following order: headers are filtered using the allowlist
+/// (if any) and the drop list, then renamed, then the configured headers are set, and finally
+/// the total size of the remaining headers is checked against the configured maximum.
+///
+/// Forwarding headers injected by the gateway are added after this policy is applied.
+#[derive(Debug, Clone, Default)]
+pub struct RequestHeaderPolicy {
+    dropped_headers: HashSet<String>,
+    allowed_headers: Option<HashSet<String>>,
+    renamed_headers: Vec<(String, String)>,
+    set_headers: Vec<(String, String)>,
+    max_total_header_bytes: Option<usize>,
+}
+
+impl RequestHeaderPolicy {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Drops the given header from requests made to canisters.
+    pub fn with_dropped_header(mut self, name: impl AsRef<str>) -> Self {
+        self.dropped_headers
+            .insert(name.as_ref().to_ascii_lowercase());
+
+        self
+    }
+
+    /// Switches the policy to allowlist mode, where only allowed headers are forwarded.
+    /// Headers that are set by the policy are forwarded regardless of the allowlist.
+    pub fn with_allowed_header(mut self, name: impl AsRef<str>) -> Self {
+        self.allowed_headers
+            .get_or_insert_with(HashSet::new)
+            .insert(name.as_ref().to_ascii_lowercase());
+
+        self
+    }
+
+    /// Renames the header `from` to `to` before forwarding it.
+    pub fn with_renamed_header(mut self, from: impl AsRef<str>, to: impl AsRef<str>) -> Self {
+        self.renamed_headers.push((
+            from.as_ref().to_ascii_lowercase(),
+            to.as_ref().to_ascii_lowercase(),
+        ));
+
+        self
+    }
+
+    /// Sets the given header on every request, replacing any value sent by the client.
+    pub fn with_set_header(mut self, name: impl AsRef<str>, value: impl Into<String>) -> Self {
+        let name = name.as_ref().to_ascii_lowercase();
+        self.set_headers.retain(|(set_name, _)| *set_name != name);
+        self.set_headers.push((name, value.into()));
+
+        self
+    }
+
+    /// Rejects requests whose forwarded headers, counting the bytes of all names and values,
+    /// exceed the given size.
+    pub fn with_max_total_header_bytes(mut self, max_total_header_bytes: usize) -> Self {
+        self.max_total_header_bytes = Some(max_total_header_bytes);
+
+        self
+    }
+
+    pub(crate) fn apply(
+        &self,
+        headers: Vec<(String, String)>,
+    ) -> HttpGatewayResult<Vec<(String, String)>> {
+        let mut headers = headers
+            .into_iter()
+            .filter(|(name, _)| {
+                let name = name.to_ascii_lowercase();
+
+                let is_allowed = self
+                    .allowed_headers
+                    .as_ref()
+                    .map_or(true, |allowed_headers| allowed_headers.contains(&name));
+
+                is_allowed && !self.dropped_headers.contains(&name)
+            })
+            .map(|(name, value)| {
+                let renamed = self
+                    .renamed_headers
+                    .iter()
+                    .find(|(from, _)| name.eq_ignore_ascii_case(from))
+                    .map(|(_, to)| to.clone());
+
+                (renamed.unwrap_or(name), value)
+            })
+            .filter(|(name, _)| {
+                !self
+                    .set_headers
+                    .iter()
+                    .any(|(set_name, _)| name.eq_ignore_ascii_case(set_name))
+            })
+            .collect::<Vec<_>>();
+
+        headers.extend(self.set_headers.iter().cloned());
+
+        if let Some(max_total_header_bytes) = self.max_total_header_bytes {
+            let total_header_bytes = headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>();
+
+            if total_header_bytes > max_total_header_bytes {
+                return Err(HttpGatewayError::RequestHeadersTooLarge {
+                    total_header_bytes,
+                    max_total_header_bytes,
+                });
+            }
+        }
+
+        Ok(headers)
+    }
+}
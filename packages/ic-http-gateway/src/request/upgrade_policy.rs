@@ -0,0 +1,266 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use candid::Principal;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Controls whether a canister may upgrade a query call to an update call.
+#[derive(Debug, Clone, Default)]
+pub enum UpgradeRule {
+    /// Upgrades are always allowed.
+    #[default]
+    Always,
+
+    /// Upgrades are never allowed.
+    Never,
+
+    /// Upgrades are only allowed for requests matching at least one of the given matchers.
+    Only(Vec<UpgradeMatcher>),
+}
+
+/// Matches requests by HTTP method and path.
+///
+/// A matcher without methods matches any method, and a matcher without a path pattern
+/// matches any path. Path patterns are matched against the path of the request, without
+/// the query string, and support `*` as a wildcard matching any sequence of characters.
+#[derive(Debug, Clone, Default)]
+pub struct UpgradeMatcher {
+    methods: Vec<String>,
+    path_pattern: Option<String>,
+}
+
+impl UpgradeMatcher {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds an HTTP method that this matcher matches.
+    pub fn with_method(mut self, method: impl AsRef<str>) -> Self {
+        self.methods.push(method.as_ref().to_ascii_uppercase());
+
+        self
+    }
+
+    /// Sets the path pattern that this matcher matches, for example `/api/*`.
+    pub fn with_path_pattern(mut self, path_pattern: impl Into<String>) -> Self {
+        self.path_pattern = Some(path_pattern.into());
+
+        self
+    }
+
+    pub(crate) fn matches(&self, method: &str, path: &str) -> bool {
+        let method_matches =
+            self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+        let path_matches = self
+            .path_pattern
+            .as_ref()
+            .map_or(true, |path_pattern| matches_pattern(path_pattern, path));
+
+        method_matches && path_matches
+    }
+}
+
+/// Controls which requests canisters may upgrade to update calls.
+///
+/// Update calls cost the canister cycles and take seconds to complete, so a gateway may want
+/// to restrict which requests can be upgraded. Upgrades that are not allowed are answered with
+/// `403 Forbidden`, and upgrades that exceed the rate limit with `429 Too Many Requests`.
+#[derive(Debug, Clone, Default)]
+pub struct UpgradePolicy {
+    default_rule: UpgradeRule,
+    canister_rules: HashMap<Principal, UpgradeRule>,
+    rate_limit: Option<(u32, Duration)>,
+    rate_limit_windows: Arc<Mutex<HashMap<Principal, (Instant, u32)>>>,
+}
+
+impl UpgradePolicy {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the rule that applies to canisters without a rule of their own.
+    pub fn with_default_rule(mut self, rule: UpgradeRule) -> Self {
+        self.default_rule = rule;
+
+        self
+    }
+
+    /// Sets the rule that applies to the given canister.
+    pub fn with_canister_rule(mut self, canister_id: Principal, rule: UpgradeRule) -> Self {
+        self.canister_rules.insert(canister_id, rule);
+
+        self
+    }
+
+    /// Allows at most `max_upgrades` upgrades per canister in each `window`.
+    /// The rate limit is shared by all clones of this policy, which only keep track of
+    /// the canisters whose window has not ended yet.
+    pub fn with_rate_limit(mut self, max_upgrades: u32, window: Duration) -> Self {
+        self.rate_limit = Some((max_upgrades, window));
+
+        self
+    }
+
+    pub(crate) fn check(
+        &self,
+        canister_id: &Principal,
+        method: &str,
+        url: &str,
+    ) -> HttpGatewayResult<()> {
+        let path = url.split('?').next().unwrap_or_default();
+
+        let is_allowed = match self
+            .canister_rules
+            .get(canister_id)
+            .unwrap_or(&self.default_rule)
+        {
+            UpgradeRule::Always => true,
+            UpgradeRule::Never => false,
+            UpgradeRule::Only(matchers) => {
+                matchers.iter().any(|matcher| matcher.matches(method, path))
+            }
+        };
+
+        if !is_allowed {
+            return Err(HttpGatewayError::UpdateCallUpgradeNotAllowed {
+                canister_id: *canister_id,
+                method: method.to_string(),
+                path: path.to_string(),
+            });
+        }
+
+        if let Some((max_upgrades, window)) = self.rate_limit {
+            let mut rate_limit_windows = self.rate_limit_windows.lock().unwrap();
+            let now = Instant::now();
+
+            // ended windows are removed, so that the windows of canisters that are no longer
+            // upgraded do not accumulate
+            rate_limit_windows
+                .retain(|_, (window_start, _)| now.duration_since(*window_start) < window);
+            let (_, upgrades) = rate_limit_windows.entry(*canister_id).or_insert((now, 0));
+
+            if *upgrades >= max_upgrades {
+                return Err(HttpGatewayError::UpdateCallUpgradeRateLimited {
+                    canister_id: *canister_id,
+                });
+            }
+            *upgrades += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // `split` always yields at least one part
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        // the pattern has no wildcard
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("/api/*", "/api/"));
+        assert!(matches_pattern("/api/*", "/api/users/1"));
+        assert!(matches_pattern("/*.json", "/a/b.json"));
+        assert!(matches_pattern("/a/*/c/*", "/a/b/c/d"));
+        assert!(matches_pattern("/exact", "/exact"));
+        assert!(!matches_pattern("/exact", "/exact/more"));
+        assert!(!matches_pattern("/api/*", "/apx/users"));
+        assert!(!matches_pattern("/*.json", "/a/b.html"));
+        assert!(!matches_pattern("/a*b*c", "/acb"));
+    }
+
+    #[test]
+    fn test_upgrade_rules() {
+        let other_canister_id = Principal::anonymous();
+        let policy = UpgradePolicy::new()
+            .with_default_rule(UpgradeRule::Never)
+            .with_canister_rule(
+                canister_id(),
+                UpgradeRule::Only(vec![
+                    UpgradeMatcher::new().with_method("post").with_method("PUT"),
+                    UpgradeMatcher::new()
+                        .with_method("GET")
+                        .with_path_pattern("/live/*"),
+                ]),
+            );
+
+        assert!(policy.check(&canister_id(), "POST", "/anything").is_ok());
+        assert!(policy.check(&canister_id(), "PUT", "/").is_ok());
+        assert!(policy
+            .check(&canister_id(), "GET", "/live/feed?x=1")
+            .is_ok());
+        assert!(matches!(
+            policy.check(&canister_id(), "GET", "/index.html?live/"),
+            Err(HttpGatewayError::UpdateCallUpgradeNotAllowed { .. })
+        ));
+        assert!(matches!(
+            policy.check(&other_canister_id, "POST", "/anything"),
+            Err(HttpGatewayError::UpdateCallUpgradeNotAllowed { .. })
+        ));
+        assert!(UpgradePolicy::new()
+            .check(&other_canister_id, "GET", "/")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_rate_limit() {
+        let other_canister_id = Principal::anonymous();
+        let policy = UpgradePolicy::new().with_rate_limit(2, Duration::from_secs(60));
+
+        assert!(policy.check(&canister_id(), "GET", "/").is_ok());
+        assert!(policy.clone().check(&canister_id(), "GET", "/").is_ok());
+        assert!(matches!(
+            policy.check(&canister_id(), "GET", "/"),
+            Err(HttpGatewayError::UpdateCallUpgradeRateLimited { .. })
+        ));
+        assert!(policy.check(&other_canister_id, "GET", "/").is_ok());
+
+        let policy = UpgradePolicy::new().with_rate_limit(1, Duration::ZERO);
+        assert!(policy.check(&canister_id(), "GET", "/").is_ok());
+        assert!(policy.check(&canister_id(), "GET", "/").is_ok());
+    }
+
+    #[test]
+    fn test_upgrade_rate_limit_windows_are_evicted() {
+        let other_canister_id = Principal::anonymous();
+        let policy = UpgradePolicy::new().with_rate_limit(1, Duration::from_millis(20));
+
+        assert!(policy.check(&canister_id(), "GET", "/").is_ok());
+        assert!(policy.check(&other_canister_id, "GET", "/").is_ok());
+        assert_eq!(policy.rate_limit_windows.lock().unwrap().len(), 2);
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert!(policy.check(&canister_id(), "GET", "/").is_ok());
+        assert_eq!(policy.rate_limit_windows.lock().unwrap().len(), 1);
+    }
+}
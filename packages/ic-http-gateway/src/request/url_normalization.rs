@@ -0,0 +1,198 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use http::Uri;
+
+/// Controls how request URLs are normalized before they are sent to the canister.
+///
+/// The normalized URL is used both for the canister call and for response verification.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UrlNormalization {
+    /// The path and query of the request are forwarded as-is.
+    #[default]
+    Disabled,
+
+    /// The URL is normalized as described in RFC 3986, section 6.2.2:
+    /// percent-encoded unreserved characters are decoded, all other percent-encodings
+    /// are uppercased and dot segments are removed from the path. URLs containing
+    /// NUL or control bytes, invalid percent-encodings or percent-encoded invalid UTF-8
+    /// are rejected. For absolute-form request targets, the authority of the URL
+    /// replaces the `Host` header, as described in RFC 9112, section 3.2.2.
+    Rfc3986,
+}
+
+pub(crate) fn normalize_url(uri: &Uri) -> HttpGatewayResult<String> {
+    if let Some(scheme) = uri.scheme_str() {
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(malformed_url(uri, "unsupported scheme"));
+        }
+    }
+
+    let path = uri.path();
+    let mut url = if path.starts_with('/') {
+        remove_dot_segments(&normalize_percent_encoding(uri, path)?)
+    } else {
+        normalize_percent_encoding(uri, path)?
+    };
+
+    if let Some(query) = uri.query() {
+        url.push('?');
+        url.push_str(&normalize_percent_encoding(uri, query)?);
+    }
+
+    Ok(url)
+}
+
+fn malformed_url(uri: &Uri, reason: &str) -> HttpGatewayError {
+    HttpGatewayError::MalformedRequestUrl {
+        url: uri.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn normalize_percent_encoding(uri: &Uri, component: &str) -> HttpGatewayResult<String> {
+    let bytes = component.as_bytes();
+    let mut normalized = String::with_capacity(component.len());
+    let mut decoded = Vec::with_capacity(component.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+
+        if byte == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| malformed_url(uri, "invalid percent-encoding"))?;
+
+            if hex.is_ascii_control() {
+                return Err(malformed_url(uri, "percent-encoded control character"));
+            }
+
+            if is_unreserved(hex) {
+                normalized.push(char::from(hex));
+            } else {
+                normalized.push_str(&format!("%{:02X}", hex));
+            }
+            decoded.push(hex);
+            i += 3;
+            continue;
+        }
+
+        if byte.is_ascii_control() {
+            return Err(malformed_url(uri, "control character"));
+        }
+
+        if byte.is_ascii() {
+            normalized.push(char::from(byte));
+        } else {
+            normalized.push_str(&format!("%{:02X}", byte));
+        }
+        decoded.push(byte);
+        i += 1;
+    }
+
+    if std::str::from_utf8(&decoded).is_err() {
+        return Err(malformed_url(uri, "invalid UTF-8"));
+    }
+
+    Ok(normalized)
+}
+
+/// Removes dot segments from an absolute path, as described in RFC 3986, section 5.2.4.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments = vec![];
+    let mut needs_trailing_slash = false;
+
+    for segment in path.split('/').skip(1) {
+        needs_trailing_slash = false;
+
+        match segment {
+            "." => needs_trailing_slash = true,
+            ".." => {
+                segments.pop();
+                needs_trailing_slash = true;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if needs_trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(uri: &str) -> HttpGatewayResult<String> {
+        normalize_url(&uri.parse::<Uri>().unwrap())
+    }
+
+    #[test]
+    fn test_normalize_url_removes_dot_segments() {
+        assert_eq!(normalize("/a/b/c/./../../g").unwrap(), "/a/g");
+        assert_eq!(normalize("/a/b/.").unwrap(), "/a/b/");
+        assert_eq!(normalize("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize("/../../etc/passwd").unwrap(), "/etc/passwd");
+        assert_eq!(normalize("/a//b/").unwrap(), "/a//b/");
+        assert_eq!(normalize("/").unwrap(), "/");
+        assert_eq!(normalize("/a/..").unwrap(), "/");
+    }
+
+    #[test]
+    fn test_normalize_url_decodes_encoded_traversal() {
+        assert_eq!(normalize("/assets/%2e%2E/secret").unwrap(), "/secret");
+        assert_eq!(normalize("/a/%2E/b").unwrap(), "/a/b");
+    }
+
+    #[test]
+    fn test_normalize_url_normalizes_percent_encoding() {
+        assert_eq!(
+            normalize("/%7euser/a%2fb/%c3%a9?q=%41%2b%20b+c").unwrap(),
+            "/~user/a%2Fb/%C3%A9?q=A%2B%20b+c"
+        );
+    }
+
+    #[test]
+    fn test_normalize_url_preserves_query_dot_segments() {
+        assert_eq!(normalize("/a/./b?path=../c").unwrap(), "/a/b?path=../c");
+    }
+
+    #[test]
+    fn test_normalize_url_absolute_form() {
+        assert_eq!(
+            normalize("https://example.com/a/../b?c=d").unwrap(),
+            "/b?c=d"
+        );
+        assert!(matches!(
+            normalize("ftp://example.com/a"),
+            Err(HttpGatewayError::MalformedRequestUrl { .. })
+        ));
+    }
+
+    #[test]
+    fn test_normalize_url_rejects_malformed_input() {
+        for uri in [
+            "/a%00b", "/a%1Fb", "/a%7f", "/a?b=%0A", "/a%", "/a%2", "/a%zz", "/a%+1", "/%C3%28",
+            "/?q=%FF",
+        ] {
+            assert!(
+                matches!(
+                    normalize(uri),
+                    Err(HttpGatewayError::MalformedRequestUrl { .. })
+                ),
+                "expected {} to be rejected",
+                uri
+            );
+        }
+    }
+}
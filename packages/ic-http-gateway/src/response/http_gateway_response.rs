@@ -3,10 +3,9 @@ use futures::stream::BoxStream;
 use http::Response;
 use http_body::Frame;
 use http_body_util::{Either, Full, StreamBody};
-use ic_agent::AgentError;
 use std::fmt::Debug;
 
-use crate::HttpGatewayError;
+use crate::{HttpGatewayError, InvalidHeaderValue, ResponseBodyStreamError};
 
 pub type CanisterResponse = Response<HttpGatewayResponseBody>;
 
@@ -21,18 +20,55 @@ pub struct HttpGatewayResponse {
 }
 
 /// Additional metadata regarding the response.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct HttpGatewayResponseMetadata {
     /// Whether the original query call was upgraded to an update call.
     pub upgraded_to_update_call: bool,
 
+    /// Whether the query call was skipped and the update call was made directly,
+    /// because the request was known to be upgraded to an update call.
+    /// If this is `true`, `upgraded_to_update_call` is also `true`.
+    pub direct_update_call: bool,
+
     /// The version of response verification that was used to verify the response.
-    /// If the protocol fails before getting to the verification step, or the
-    /// original query call is upgraded to an update call, this field will be `None`.
+    /// If the protocol fails before getting to the verification step, this field will be `None`.
+    /// Replies to update calls are certified by the state tree instead, which is reported by
+    /// `update_response_verified`, so this field is also `None` if the original query call
+    /// is upgraded to an update call.
     pub response_verification_version: Option<u16>,
 
+    /// Whether the reply to the update call was taken from its certified status, that is,
+    /// from `request_status/<request_id>/reply` of a `read_state` certificate for the requested
+    /// canister that was verified against the root key.
+    /// Headers of responses to update calls are still sanitized, because the certificate does
+    /// not cover the chunks of streamed bodies, which are fetched with query calls.
+    /// This is always `false` if the original query call was not upgraded to an update call.
+    pub update_response_verified: bool,
+
+    /// Whether the gateway verified the signatures of the replica nodes on the response to the
+    /// query call, so that the response is attributable to a node even if it is not certified.
+    /// The responses to streaming callbacks are verified the same way.
+    /// This is `false` if the query call was skipped or query signature verification is not enabled
+    /// on the client, even if the agent verified the signatures according to its own configuration.
+    pub query_signature_verified: bool,
+
     /// The internal error that resulted in the HTTP response being an error response.
     pub internal_error: Option<HttpGatewayError>,
+
+    /// The URL of the endpoint that the request was routed to.
+    /// This is `None` if the client routes requests through an agent instead of an endpoint pool.
+    pub endpoint: Option<String>,
+
+    /// The URL of the subnet node endpoint that query calls were sent to, if subnet routing is enabled
+    /// and the requested canister is part of the routing table.
+    pub subnet_endpoint: Option<String>,
+
+    /// The URLs of the endpoints that the request was tried on before, which failed with a transport error.
+    pub failed_endpoints: Vec<String>,
+
+    /// Request and response header values that could not be represented as-is,
+    /// along with the action that was taken for each of them.
+    pub invalid_header_values: Vec<InvalidHeaderValue>,
 }
 
 pub type HttpGatewayResponseBody = Either<ResponseBodyStream, Full<Bytes>>;
@@ -40,4 +76,4 @@ pub type HttpGatewayResponseBody = Either<ResponseBodyStream, Full<Bytes>>;
 pub type ResponseBodyStream = StreamBody<BoxStream<'static, ResponseBodyStreamItem>>;
 
 /// An item in a response body stream.
-pub type ResponseBodyStreamItem = Result<Frame<Bytes>, AgentError>;
+pub type ResponseBodyStreamItem = Result<Frame<Bytes>, ResponseBodyStreamError>;
@@ -0,0 +1,149 @@
+use crate::{
+    ResponseBodyStreamItem, CERTIFIED_TRAILER_NAME, CHUNK_COUNT_TRAILER_NAME,
+    CONTENT_DIGEST_TRAILER_NAME, TE_HEADER_NAME,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::{stream, Stream, StreamExt};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use http_body::Frame;
+use sha2::{Digest, Sha256};
+
+/// Returns whether the client accepts trailers, as advertised with `TE: trailers`.
+pub(crate) fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE_HEADER_NAME)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|te| {
+            te.split(';')
+                .next()
+                .is_some_and(|te| te.trim().eq_ignore_ascii_case("trailers"))
+        })
+}
+
+/// The value of the `Trailer` response header announcing the integrity trailers.
+pub(crate) fn integrity_trailer_names() -> String {
+    [
+        CONTENT_DIGEST_TRAILER_NAME,
+        CHUNK_COUNT_TRAILER_NAME,
+        CERTIFIED_TRAILER_NAME,
+    ]
+    .join(", ")
+}
+
+/// Computes a running SHA-256 digest over the data frames of `body_stream` and appends a
+/// trailers frame with the digest (as a `Content-Digest` field, see RFC 9530), the number of
+/// data frames and whether the streamed content was certified. No trailers are sent if the
+/// stream fails.
+pub(crate) fn with_integrity_trailers(
+    body_stream: impl Stream<Item = ResponseBodyStreamItem> + Send + 'static,
+    certified: bool,
+) -> impl Stream<Item = ResponseBodyStreamItem> {
+    stream::unfold(
+        Some((Box::pin(body_stream), Sha256::new(), 0usize)),
+        move |state| async move {
+            let (mut body_stream, mut hasher, mut chunk_count) = state?;
+
+            match body_stream.next().await {
+                Some(Ok(frame)) => {
+                    if let Some(data) = frame.data_ref() {
+                        hasher.update(data);
+                        chunk_count += 1;
+                    }
+
+                    Some((Ok(frame), Some((body_stream, hasher, chunk_count))))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None => {
+                    let trailers = integrity_trailers(&hasher.finalize(), chunk_count, certified);
+
+                    Some((Ok(Frame::trailers(trailers)), None))
+                }
+            }
+        },
+    )
+}
+
+fn integrity_trailers(digest: &[u8], chunk_count: usize, certified: bool) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+
+    // base64 only produces valid header value characters
+    if let Ok(content_digest) =
+        HeaderValue::from_str(&format!("sha-256=:{}:", BASE64.encode(digest)))
+    {
+        trailers.insert(
+            HeaderName::from_static(CONTENT_DIGEST_TRAILER_NAME),
+            content_digest,
+        );
+    }
+    trailers.insert(
+        HeaderName::from_static(CHUNK_COUNT_TRAILER_NAME),
+        HeaderValue::from(chunk_count),
+    );
+    // structured field booleans, see RFC 8941, section 3.3.6
+    trailers.insert(
+        HeaderName::from_static(CERTIFIED_TRAILER_NAME),
+        HeaderValue::from_static(if certified { "?1" } else { "?0" }),
+    );
+
+    trailers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResponseBodyStreamError;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_accepts_trailers() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_trailers(&headers));
+
+        headers.insert("te", HeaderValue::from_static("gzip;q=0.5"));
+        assert!(!accepts_trailers(&headers));
+
+        headers.append("te", HeaderValue::from_static("deflate, Trailers"));
+        assert!(accepts_trailers(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_with_integrity_trailers() {
+        let body_stream = stream::iter(vec![
+            Ok(Frame::data(Bytes::from_static(b"hello "))),
+            Ok(Frame::data(Bytes::from_static(b"world"))),
+        ]);
+
+        let frames = with_integrity_trailers(body_stream, false)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(frames.len(), 3);
+        let trailers = frames[2].as_ref().unwrap().trailers_ref().unwrap();
+        assert_eq!(
+            trailers.get(CONTENT_DIGEST_TRAILER_NAME).unwrap(),
+            // sha256("hello world")
+            "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:"
+        );
+        assert_eq!(trailers.get(CHUNK_COUNT_TRAILER_NAME).unwrap(), "2");
+        assert_eq!(trailers.get(CERTIFIED_TRAILER_NAME).unwrap(), "?0");
+    }
+
+    #[tokio::test]
+    async fn test_with_integrity_trailers_omits_trailers_on_error() {
+        let body_stream = stream::iter(vec![
+            Ok(Frame::data(Bytes::from_static(b"hello "))),
+            Err(ResponseBodyStreamError::CallbackCallLimitReached {
+                max_callback_calls: 1,
+            }),
+        ]);
+
+        let frames = with_integrity_trailers(body_stream, false)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].is_err());
+    }
+}
@@ -3,3 +3,21 @@ pub use http_gateway_response::*;
 
 mod response_handler;
 pub use response_handler::*;
+
+mod response_header_sanitizer;
+pub use response_header_sanitizer::*;
+
+mod streaming_prefetch;
+pub use streaming_prefetch::*;
+
+mod streaming_limits;
+pub use streaming_limits::*;
+
+mod streaming_callback_policy;
+pub use streaming_callback_policy::*;
+
+mod response_body_stream_error;
+pub use response_body_stream_error::*;
+
+mod integrity_trailers;
+pub(crate) use integrity_trailers::*;
@@ -0,0 +1,96 @@
+use ic_agent::AgentError;
+use std::sync::Arc;
+
+/// An error that ends a response body stream before the last chunk has been streamed.
+///
+/// Servers should abort the response when they receive this error, so that the client
+/// does not mistake the truncated body for a complete one.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum ResponseBodyStreamError {
+    /// The canister rejected a streaming callback call.
+    #[error("Streaming callback was rejected: {0}")]
+    CallbackRejected(Arc<AgentError>),
+
+    /// A streaming callback call failed for a reason other than a reject or a failed verification,
+    /// such as a transport error or a malformed reply.
+    #[error("Streaming callback failed: {0}")]
+    CallbackFailed(Arc<AgentError>),
+
+    /// The maximum number of streaming callback calls was reached.
+    #[error("Streaming callback call limit of {max_callback_calls} reached")]
+    CallbackCallLimitReached { max_callback_calls: usize },
+
+    /// The streamed body exceeds the maximum body size.
+    #[error("Streamed body exceeds the limit of {max_body_bytes} bytes")]
+    BodySizeLimitExceeded { max_body_bytes: usize },
+
+    /// The signatures of the replica nodes on the response to a streaming callback call
+    /// could not be verified.
+    #[error("Streamed chunk failed verification: {0}")]
+    VerificationFailed(Arc<AgentError>),
+}
+
+impl From<AgentError> for ResponseBodyStreamError {
+    fn from(err: AgentError) -> Self {
+        match err {
+            AgentError::CertifiedReject(_) | AgentError::UncertifiedReject(_) => {
+                ResponseBodyStreamError::CallbackRejected(Arc::new(err))
+            }
+            _ if is_query_signature_error(&err) => {
+                ResponseBodyStreamError::VerificationFailed(Arc::new(err))
+            }
+            _ => ResponseBodyStreamError::CallbackFailed(Arc::new(err)),
+        }
+    }
+}
+
+/// Returns whether a query call failed because the signatures on its response could not be verified.
+pub(crate) fn is_query_signature_error(err: &AgentError) -> bool {
+    matches!(
+        err,
+        AgentError::MissingSignature
+            | AgentError::MalformedSignature
+            | AgentError::MalformedPublicKey
+            | AgentError::TooManySignatures { .. }
+            | AgentError::QuerySignatureVerificationFailed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_agent::agent::{RejectCode, RejectResponse};
+
+    #[test]
+    fn test_response_body_stream_error_is_send_and_sync() {
+        fn assert_send_sync<T: std::error::Error + Send + Sync + 'static>() {}
+
+        assert_send_sync::<ResponseBodyStreamError>();
+    }
+
+    #[test]
+    fn test_from_agent_error() {
+        let reject = AgentError::CertifiedReject(RejectResponse {
+            reject_code: RejectCode::CanisterError,
+            reject_message: "trapped".to_string(),
+            error_code: None,
+        });
+
+        assert!(matches!(
+            ResponseBodyStreamError::from(reject),
+            ResponseBodyStreamError::CallbackRejected(_)
+        ));
+        assert!(matches!(
+            ResponseBodyStreamError::from(AgentError::TimeoutWaitingForResponse()),
+            ResponseBodyStreamError::CallbackFailed(_)
+        ));
+        assert!(matches!(
+            ResponseBodyStreamError::from(AgentError::QuerySignatureVerificationFailed),
+            ResponseBodyStreamError::VerificationFailed(_)
+        ));
+        assert!(matches!(
+            ResponseBodyStreamError::from(AgentError::MissingSignature),
+            ResponseBodyStreamError::VerificationFailed(_)
+        ));
+    }
+}
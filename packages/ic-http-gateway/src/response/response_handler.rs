@@ -1,61 +1,104 @@
-use crate::{HttpGatewayResponseBody, ResponseBodyStream};
+use crate::{
+    create_prefetch_stream, is_query_signature_error, protocol::stream_callback_query,
+    with_integrity_trailers, HttpGatewayError, HttpGatewayResponseBody, HttpGatewayResult,
+    ResponseBodyStream, ResponseBodyStreamError, StreamingCallbackPolicy, StreamingLimits,
+    StreamingPrefetch,
+};
 use bytes::Bytes;
-use futures::{stream, Stream, StreamExt, TryStreamExt};
+use candid::Principal;
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
 use http_body::Frame;
 use http_body_util::Full;
 use ic_agent::{Agent, AgentError};
-use ic_utils::{
-    call::SyncCall,
-    interfaces::http_request::{
-        HttpRequestCanister, HttpRequestStreamingCallbackAny, HttpResponse as AgentResponse,
-        StreamingCallbackHttpResponse, StreamingStrategy, Token,
-    },
+use ic_utils::interfaces::http_request::{
+    HttpRequestStreamingCallbackAny, HttpResponse as AgentResponse, StreamingStrategy, Token,
 };
+use std::sync::Arc;
 
-// Limit the total number of calls to an HTTP Request loop to 1000 for now.
-static MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT: usize = 1000;
+pub type AgentResponseAny = AgentResponse<Token, HttpRequestStreamingCallbackAny>;
 
-// Limit the total number of calls to an HTTP request look that can be verified
-static MAX_VERIFIED_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT: usize = 4;
+/// Controls how the body of a response that uses the streaming callback strategy is streamed.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingBodyOptions<'a> {
+    pub streaming_prefetch: StreamingPrefetch,
+    pub streaming_limits: StreamingLimits,
+    pub streaming_callback_policy: &'a StreamingCallbackPolicy,
 
-// Limit the number of Stream Callbacks buffered
-static STREAM_CALLBACK_BUFFER: usize = 2;
+    /// Whether the signatures of the replica nodes on the responses to streaming callbacks are
+    /// verified, like those on the response to `http_request`.
+    pub verify_query_signatures: bool,
 
-pub type AgentResponseAny = AgentResponse<Token, HttpRequestStreamingCallbackAny>;
+    /// Whether integrity trailers are added to bodies that are streamed to the client.
+    pub integrity_trailers: bool,
+}
 
+/// Returns the body of `response`, which is streamed if the response uses the streaming callback
+/// strategy. The body is not copied.
 pub async fn get_body_and_streaming_body(
     agent: &Agent,
-    response: &AgentResponseAny,
-) -> Result<HttpGatewayResponseBody, AgentError> {
+    canister_id: &Principal,
+    response: AgentResponseAny,
+    options: StreamingBodyOptions<'_>,
+) -> HttpGatewayResult<HttpGatewayResponseBody> {
+    let StreamingBodyOptions {
+        streaming_prefetch,
+        streaming_limits,
+        streaming_callback_policy,
+        verify_query_signatures,
+        integrity_trailers,
+    } = options;
+    let AgentResponseAny {
+        body,
+        streaming_strategy,
+        ..
+    } = response;
+
     // if we already have the full body, we can return it early
-    let Some(StreamingStrategy::Callback(callback_strategy)) = response.streaming_strategy.clone()
-    else {
-        return Ok(HttpGatewayResponseBody::Right(Full::from(
-            response.body.clone(),
-        )));
+    let Some(StreamingStrategy::Callback(callback_strategy)) = streaming_strategy else {
+        return Ok(HttpGatewayResponseBody::Right(Full::new(Bytes::from(body))));
     };
 
-    let (streamed_body, token) = create_stream(
+    streaming_callback_policy.check(canister_id, &callback_strategy.callback.0.principal)?;
+
+    // the initial body is usually as large as the chunks returned by streaming callbacks
+    let chunk_size_estimate = body.len();
+    let (streamed_body, token, callback_calls) = create_stream(
         agent.clone(),
         callback_strategy.callback.clone(),
         Some(callback_strategy.token),
+        streaming_prefetch,
+        chunk_size_estimate,
+        verify_query_signatures,
     )
-    .take(MAX_VERIFIED_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT)
-    .map(|x| async move { x })
-    .buffered(STREAM_CALLBACK_BUFFER)
+    .take(streaming_limits.max_verified_callback_calls())
+    .map_err(|e| {
+        if is_query_signature_error(&e) {
+            return ResponseBodyStreamError::VerificationFailed(Arc::new(e)).into();
+        }
+
+        HttpGatewayError::from(e)
+    })
     .try_fold(
-        (vec![], None::<Token>),
+        (body, None::<Token>, 0),
         |mut accum, (mut body, token)| async move {
             accum.0.append(&mut body);
             accum.1 = token;
+            accum.2 += 1;
+
+            if let Some(max_body_bytes) = streaming_limits.exceeded_max_body_bytes(accum.0.len()) {
+                return Err(
+                    ResponseBodyStreamError::BodySizeLimitExceeded { max_body_bytes }.into(),
+                );
+            }
 
             Ok(accum)
         },
     )
     .await?;
 
-    let streamed_body = [response.body.clone(), streamed_body].concat();
-
     // if we still have a token at this point,
     // we were unable to collect the response within the allowed certified callback limit,
     // fallback to uncertified streaming using what we've streamed so far as the initial body
@@ -65,6 +108,14 @@ pub async fn get_body_and_streaming_body(
             callback_strategy.callback,
             token,
             streamed_body,
+            BodyStreamOptions {
+                streaming_prefetch,
+                chunk_size_estimate,
+                streaming_limits,
+                callback_calls,
+                verify_query_signatures,
+                integrity_trailers,
+            },
         );
 
         return Ok(HttpGatewayResponseBody::Left(body_stream));
@@ -73,7 +124,19 @@ pub async fn get_body_and_streaming_body(
     // if we no longer have a token at this point,
     // we were able to collect the response within the allow certified callback limit,
     // return this collected response as a standard response body so it will be verified
-    Ok(HttpGatewayResponseBody::Right(Full::from(streamed_body)))
+    Ok(HttpGatewayResponseBody::Right(Full::new(Bytes::from(
+        streamed_body,
+    ))))
+}
+
+struct BodyStreamOptions {
+    streaming_prefetch: StreamingPrefetch,
+    chunk_size_estimate: usize,
+    streaming_limits: StreamingLimits,
+    // the number of callback calls made before falling back to streaming
+    callback_calls: usize,
+    verify_query_signatures: bool,
+    integrity_trailers: bool,
 }
 
 fn create_body_stream(
@@ -81,42 +144,160 @@ fn create_body_stream(
     callback: HttpRequestStreamingCallbackAny,
     token: Option<Token>,
     initial_body: Vec<u8>,
+    options: BodyStreamOptions,
 ) -> ResponseBodyStream {
-    let chunks_stream = create_stream(agent, callback, token)
-        .map(|chunk| chunk.map(|(body, _)| Frame::data(Bytes::from(body))));
+    let body_bytes = initial_body.len();
+    let chunks_stream = create_stream(
+        agent,
+        callback,
+        token,
+        options.streaming_prefetch,
+        options.chunk_size_estimate,
+        options.verify_query_signatures,
+    );
+    let chunks_stream = limit_stream(
+        Box::pin(chunks_stream),
+        options.streaming_limits,
+        options.callback_calls,
+        body_bytes,
+    )
+    .map(|chunk| chunk.map(|body| Frame::data(Bytes::from(body))))
+    .inspect(|chunk| {
+        if let Err(e) = chunk {
+            tracing::warn!(error = %e, "Response body stream failed");
+        }
+    });
 
     let body_stream = stream::once(async move { Ok(Frame::data(Bytes::from(initial_body))) })
-        .chain(chunks_stream)
-        .take(MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT)
-        .map(|x| async move { x })
-        .buffered(STREAM_CALLBACK_BUFFER);
+        .chain(chunks_stream);
+
+    if options.integrity_trailers {
+        // the chunks returned by streaming callbacks are never certified
+        return ResponseBodyStream::new(Box::pin(with_integrity_trailers(body_stream, false)));
+    }
 
     ResponseBodyStream::new(Box::pin(body_stream))
 }
 
+/// Ends the stream with an error if a limit is reached before the last chunk has been streamed.
+fn limit_stream(
+    chunks_stream: BoxStream<'static, Result<(Vec<u8>, Option<Token>), AgentError>>,
+    streaming_limits: StreamingLimits,
+    callback_calls: usize,
+    body_bytes: usize,
+) -> impl Stream<Item = Result<Vec<u8>, ResponseBodyStreamError>> {
+    stream::unfold(
+        Some((chunks_stream, callback_calls, body_bytes)),
+        move |state| async move {
+            let (mut chunks_stream, callback_calls, body_bytes) = state?;
+
+            if callback_calls >= streaming_limits.max_callback_calls() {
+                return Some((
+                    Err(ResponseBodyStreamError::CallbackCallLimitReached {
+                        max_callback_calls: streaming_limits.max_callback_calls(),
+                    }),
+                    None,
+                ));
+            }
+
+            let (body, token) = match chunks_stream.next().await? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some((Err(e.into()), None)),
+            };
+
+            let callback_calls = callback_calls + 1;
+            let body_bytes = body_bytes + body.len();
+            if let Some(max_body_bytes) = streaming_limits.exceeded_max_body_bytes(body_bytes) {
+                return Some((
+                    Err(ResponseBodyStreamError::BodySizeLimitExceeded { max_body_bytes }),
+                    None,
+                ));
+            }
+
+            // the stream ends after the chunk without a token
+            let state = token.map(|_| (chunks_stream, callback_calls, body_bytes));
+
+            Some((Ok(body), state))
+        },
+    )
+}
+
 fn create_stream(
     agent: Agent,
     callback: HttpRequestStreamingCallbackAny,
     token: Option<Token>,
+    streaming_prefetch: StreamingPrefetch,
+    chunk_size_estimate: usize,
+    verify_query_signatures: bool,
 ) -> impl Stream<Item = Result<(Vec<u8>, Option<Token>), AgentError>> {
-    futures::stream::try_unfold(
-        (agent, callback, token),
-        |(agent, callback, token)| async move {
-            let Some(token) = token else {
-                return Ok(None);
-            };
+    create_prefetch_stream(
+        move |token| {
+            let agent = agent.clone();
+            let callback = callback.clone();
 
-            let canister = HttpRequestCanister::create(&agent, callback.0.principal);
-            match canister
-                .http_request_stream_callback(&callback.0.method, token)
-                .call()
-                .await
-            {
-                Ok((StreamingCallbackHttpResponse { body, token },)) => {
-                    Ok(Some(((body, token.clone()), (agent, callback, token))))
-                }
-                Err(e) => Err(e),
-            }
+            async move { stream_callback_query(&agent, &callback, token, verify_query_signatures).await }
         },
+        token,
+        streaming_prefetch,
+        chunk_size_estimate,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::types::value::IDLValue;
+
+    fn chunks_stream(
+        chunk_count: usize,
+    ) -> BoxStream<'static, Result<(Vec<u8>, Option<Token>), AgentError>> {
+        Box::pin(stream::iter((0..chunk_count).map(move |i| {
+            let token = (i + 1 < chunk_count).then(|| Token(IDLValue::Nat64(i as u64 + 1)));
+
+            Ok((vec![i as u8; 10], token))
+        })))
+    }
+
+    async fn collect_limited(
+        chunk_count: usize,
+        streaming_limits: StreamingLimits,
+    ) -> Vec<Result<Vec<u8>, ResponseBodyStreamError>> {
+        limit_stream(chunks_stream(chunk_count), streaming_limits, 0, 0)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream_within_limits() {
+        let chunks = collect_limited(3, StreamingLimits::new().with_max_callback_calls(3)).await;
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream_signals_callback_call_limit() {
+        let chunks = collect_limited(5, StreamingLimits::new().with_max_callback_calls(3)).await;
+
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks[..3].iter().all(|chunk| chunk.is_ok()));
+        assert!(matches!(
+            chunks[3],
+            Err(ResponseBodyStreamError::CallbackCallLimitReached {
+                max_callback_calls: 3
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream_signals_body_bytes_limit() {
+        let chunks = collect_limited(5, StreamingLimits::new().with_max_body_bytes(25)).await;
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[..2].iter().all(|chunk| chunk.is_ok()));
+        assert!(matches!(
+            chunks[2],
+            Err(ResponseBodyStreamError::BodySizeLimitExceeded { max_body_bytes: 25 })
+        ));
+    }
+}
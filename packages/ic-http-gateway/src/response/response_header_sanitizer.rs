@@ -0,0 +1,233 @@
+use crate::{CONNECTION_HEADER_NAME, DANGEROUS_RESPONSE_HEADER_NAMES, HOP_BY_HOP_HEADER_NAMES};
+use candid::Principal;
+use std::collections::{HashMap, HashSet};
+
+/// Sanitizes the headers of responses that are not fully certified.
+///
+/// This applies to responses that skipped verification, responses verified with
+/// response verification v1 and responses where the canister has certifiably
+/// skipped certification. Responses with certified headers are returned as-is.
+#[derive(Debug, Clone)]
+pub struct ResponseHeaderSanitizer {
+    denied_headers: HashSet<String>,
+    strip_hop_by_hop_headers: bool,
+    enforced_headers: Vec<(String, String)>,
+    canister_allowed_headers: HashMap<Principal, HashSet<String>>,
+}
+
+impl ResponseHeaderSanitizer {
+    /// Creates a sanitizer that only strips hop-by-hop headers.
+    pub fn new() -> Self {
+        Self {
+            denied_headers: HashSet::new(),
+            strip_hop_by_hop_headers: true,
+            enforced_headers: vec![],
+            canister_allowed_headers: HashMap::new(),
+        }
+    }
+
+    /// Creates a sanitizer that strips hop-by-hop headers and denies headers that
+    /// allow an uncertified response to set cookies, redirect the client or
+    /// change browser security policies.
+    pub fn strict() -> Self {
+        DANGEROUS_RESPONSE_HEADER_NAMES
+            .iter()
+            .fold(Self::new(), |sanitizer, name| {
+                sanitizer.with_denied_header(*name)
+            })
+    }
+
+    /// Removes the given header from responses that are not fully certified.
+    pub fn with_denied_header(mut self, name: impl AsRef<str>) -> Self {
+        self.denied_headers
+            .insert(name.as_ref().to_ascii_lowercase());
+
+        self
+    }
+
+    /// Sets whether hop-by-hop headers, including those listed in the `Connection` header,
+    /// are removed from responses that are not fully certified.
+    pub fn with_strip_hop_by_hop_headers(mut self, strip_hop_by_hop_headers: bool) -> Self {
+        self.strip_hop_by_hop_headers = strip_hop_by_hop_headers;
+
+        self
+    }
+
+    /// Adds the given header to responses that are not fully certified,
+    /// replacing any value set by the canister.
+    pub fn with_enforced_header(mut self, name: impl AsRef<str>, value: impl Into<String>) -> Self {
+        let name = name.as_ref().to_ascii_lowercase();
+        self.enforced_headers
+            .retain(|(enforced_name, _)| *enforced_name != name);
+        self.enforced_headers.push((name, value.into()));
+
+        self
+    }
+
+    /// Allows the given canister to return a header that would otherwise be denied.
+    /// Hop-by-hop headers are stripped regardless of this allowlist.
+    pub fn with_allowed_header_for_canister(
+        mut self,
+        canister_id: Principal,
+        name: impl AsRef<str>,
+    ) -> Self {
+        self.canister_allowed_headers
+            .entry(canister_id)
+            .or_default()
+            .insert(name.as_ref().to_ascii_lowercase());
+
+        self
+    }
+
+    pub(crate) fn sanitize<'a>(
+        &self,
+        canister_id: &Principal,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Vec<(String, String)> {
+        let headers = headers.into_iter().collect::<Vec<_>>();
+        let connection_headers = if self.strip_hop_by_hop_headers {
+            get_connection_header_names(&headers)
+        } else {
+            HashSet::new()
+        };
+        let allowed_headers = self.canister_allowed_headers.get(canister_id);
+
+        let mut sanitized_headers = headers
+            .into_iter()
+            .filter(|(name, _)| {
+                let name = name.to_ascii_lowercase();
+
+                if self.strip_hop_by_hop_headers
+                    && (HOP_BY_HOP_HEADER_NAMES.contains(&name.as_str())
+                        || connection_headers.contains(&name))
+                {
+                    return false;
+                }
+
+                if self
+                    .enforced_headers
+                    .iter()
+                    .any(|(enforced_name, _)| *enforced_name == name)
+                {
+                    return false;
+                }
+
+                !self.denied_headers.contains(&name)
+                    || allowed_headers
+                        .is_some_and(|allowed_headers| allowed_headers.contains(&name))
+            })
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+
+        sanitized_headers.extend(self.enforced_headers.iter().cloned());
+
+        sanitized_headers
+    }
+}
+
+impl Default for ResponseHeaderSanitizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn get_connection_header_names(headers: &[(&str, &str)]) -> HashSet<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(CONNECTION_HEADER_NAME))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_sanitize_strips_hop_by_hop_headers() {
+        let sanitizer = ResponseHeaderSanitizer::new();
+
+        let headers = sanitizer.sanitize(
+            &canister_id(),
+            vec![
+                ("Connection", "keep-alive, X-Custom-Hop"),
+                ("Keep-Alive", "timeout=5"),
+                ("Transfer-Encoding", "chunked"),
+                ("X-Custom-Hop", "1"),
+                ("Content-Type", "text/html"),
+            ],
+        );
+
+        assert_eq!(
+            headers,
+            vec![("Content-Type".to_string(), "text/html".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_sanitize_keeps_hop_by_hop_headers_when_disabled() {
+        let sanitizer = ResponseHeaderSanitizer::new().with_strip_hop_by_hop_headers(false);
+
+        let headers = sanitizer.sanitize(&canister_id(), vec![("connection", "close")]);
+
+        assert_eq!(
+            headers,
+            vec![("connection".to_string(), "close".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_sanitize_denied_and_allowed_headers() {
+        let other_canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let sanitizer = ResponseHeaderSanitizer::strict()
+            .with_allowed_header_for_canister(canister_id(), "Set-Cookie");
+        let headers = vec![
+            ("set-cookie", "session=1"),
+            ("location", "https://example.com"),
+            ("content-type", "text/html"),
+        ];
+
+        assert_eq!(
+            sanitizer.sanitize(&canister_id(), headers.clone()),
+            vec![
+                ("set-cookie".to_string(), "session=1".to_string()),
+                ("content-type".to_string(), "text/html".to_string()),
+            ]
+        );
+        assert_eq!(
+            sanitizer.sanitize(&other_canister_id, headers),
+            vec![("content-type".to_string(), "text/html".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_sanitize_enforced_headers() {
+        let sanitizer = ResponseHeaderSanitizer::new()
+            .with_enforced_header("X-Content-Type-Options", "nosniff")
+            .with_enforced_header("x-frame-options", "SAMEORIGIN")
+            .with_enforced_header("X-Frame-Options", "DENY");
+
+        let headers = sanitizer.sanitize(
+            &canister_id(),
+            vec![
+                ("x-frame-options", "ALLOWALL"),
+                ("content-type", "text/html"),
+            ],
+        );
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/html".to_string()),
+                ("x-content-type-options".to_string(), "nosniff".to_string()),
+                ("x-frame-options".to_string(), "DENY".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{HttpGatewayError, HttpGatewayResult};
+use candid::Principal;
+use std::collections::{HashMap, HashSet};
+
+/// Controls which canisters may be called by the streaming callbacks of a canister's responses.
+///
+/// By default, the streaming callback must target the canister that the request was made to,
+/// so that a canister cannot stream the content of an unrelated canister under its own domain.
+/// Responses with a streaming callback that is not allowed are answered with `502 Bad Gateway`.
+#[derive(Debug, Clone, Default)]
+pub struct StreamingCallbackPolicy {
+    allowed_callback_canisters: HashMap<Principal, HashSet<Principal>>,
+}
+
+impl StreamingCallbackPolicy {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Allows the responses of `canister_id` to stream content from `callback_canister_id`.
+    pub fn with_allowed_callback_canister(
+        mut self,
+        canister_id: Principal,
+        callback_canister_id: Principal,
+    ) -> Self {
+        self.allowed_callback_canisters
+            .entry(canister_id)
+            .or_default()
+            .insert(callback_canister_id);
+
+        self
+    }
+
+    pub(crate) fn check(
+        &self,
+        canister_id: &Principal,
+        callback_canister_id: &Principal,
+    ) -> HttpGatewayResult<()> {
+        let is_allowed = canister_id == callback_canister_id
+            || self
+                .allowed_callback_canisters
+                .get(canister_id)
+                .is_some_and(|allowed| allowed.contains(callback_canister_id));
+
+        if !is_allowed {
+            return Err(HttpGatewayError::StreamingCallbackCanisterNotAllowed {
+                canister_id: *canister_id,
+                callback_canister_id: *callback_canister_id,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+    }
+
+    fn other_canister_id() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_same_canister_is_allowed() {
+        let policy = StreamingCallbackPolicy::new();
+
+        assert!(policy.check(&canister_id(), &canister_id()).is_ok());
+        assert!(matches!(
+            policy.check(&canister_id(), &other_canister_id()),
+            Err(HttpGatewayError::StreamingCallbackCanisterNotAllowed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_allowed_callback_canister() {
+        let policy = StreamingCallbackPolicy::new()
+            .with_allowed_callback_canister(canister_id(), other_canister_id());
+
+        assert!(policy.check(&canister_id(), &other_canister_id()).is_ok());
+        assert!(matches!(
+            policy.check(&other_canister_id(), &canister_id()),
+            Err(HttpGatewayError::StreamingCallbackCanisterNotAllowed { .. })
+        ));
+    }
+}
@@ -0,0 +1,68 @@
+const DEFAULT_MAX_CALLBACK_CALLS: usize = 1000;
+const DEFAULT_MAX_VERIFIED_CALLBACK_CALLS: usize = 4;
+
+/// Limits that apply to responses using the streaming callback strategy.
+///
+/// If a limit is reached before the canister has returned the last chunk, the response body
+/// stream ends with an error instead of ending cleanly, so that the connection to the client
+/// is aborted and the client does not mistake the truncated body for a complete one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingLimits {
+    max_callback_calls: usize,
+    max_verified_callback_calls: usize,
+    max_body_bytes: Option<usize>,
+}
+
+impl StreamingLimits {
+    pub fn new() -> Self {
+        Self {
+            max_callback_calls: DEFAULT_MAX_CALLBACK_CALLS,
+            max_verified_callback_calls: DEFAULT_MAX_VERIFIED_CALLBACK_CALLS,
+            max_body_bytes: None,
+        }
+    }
+
+    /// Sets the maximum number of streaming callback calls made for a single response.
+    pub fn with_max_callback_calls(mut self, max_callback_calls: usize) -> Self {
+        self.max_callback_calls = max_callback_calls;
+
+        self
+    }
+
+    /// Sets the maximum number of streaming callback calls whose chunks are collected
+    /// so that the full response can be verified. Responses that need more calls are
+    /// streamed to the client without verification.
+    pub fn with_max_verified_callback_calls(mut self, max_verified_callback_calls: usize) -> Self {
+        self.max_verified_callback_calls = max_verified_callback_calls;
+
+        self
+    }
+
+    /// Sets the maximum size of a streamed response body, including the initial body.
+    pub fn with_max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = Some(max_body_bytes);
+
+        self
+    }
+
+    pub(crate) fn max_callback_calls(&self) -> usize {
+        self.max_callback_calls
+    }
+
+    pub(crate) fn max_verified_callback_calls(&self) -> usize {
+        self.max_verified_callback_calls
+            .min(self.max_callback_calls)
+    }
+
+    /// Returns the maximum body size if `body_bytes` exceeds it.
+    pub(crate) fn exceeded_max_body_bytes(&self, body_bytes: usize) -> Option<usize> {
+        self.max_body_bytes
+            .filter(|max_body_bytes| body_bytes > *max_body_bytes)
+    }
+}
+
+impl Default for StreamingLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,391 @@
+use candid::types::{
+    value::{IDLField, IDLValue},
+    Label,
+};
+use futures::{
+    stream::{self, FuturesOrdered},
+    Future, Stream, StreamExt,
+};
+use ic_agent::AgentError;
+use ic_utils::interfaces::http_request::Token;
+use std::collections::VecDeque;
+
+type ChunkResult = Result<(Vec<u8>, Option<Token>), AgentError>;
+
+const DEFAULT_MAX_BYTES_IN_FLIGHT: usize = 8 * 1024 * 1024;
+
+/// Controls the concurrent prefetching of streaming callback chunks.
+///
+/// Each streaming callback needs the token returned by the previous one, so chunks are
+/// normally fetched one at a time. If the canister uses predictable tokens, such as the
+/// `index` field of the tokens returned by the standard asset canister, the next tokens
+/// can be predicted and the corresponding chunks fetched ahead of time. A prefetched chunk
+/// is only used if the token returned by the previous chunk matches the prediction,
+/// otherwise the prefetched chunks are discarded and streaming continues sequentially.
+///
+/// The number of chunks in flight is bounded by `max_bytes_in_flight`, estimated using the
+/// size of the most recently received chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingPrefetch {
+    prefetch_chunks: usize,
+    max_bytes_in_flight: usize,
+}
+
+impl StreamingPrefetch {
+    /// Chunks are fetched one at a time.
+    pub fn sequential() -> Self {
+        Self {
+            prefetch_chunks: 0,
+            max_bytes_in_flight: DEFAULT_MAX_BYTES_IN_FLIGHT,
+        }
+    }
+
+    /// Fetches up to `prefetch_chunks` chunks ahead of the chunk that is currently needed.
+    pub fn new(prefetch_chunks: usize) -> Self {
+        Self {
+            prefetch_chunks,
+            ..Self::sequential()
+        }
+    }
+
+    /// Sets the maximum number of bytes that may be in flight at the same time.
+    /// At least one chunk is always fetched, regardless of its size.
+    pub fn with_max_bytes_in_flight(mut self, max_bytes_in_flight: usize) -> Self {
+        self.max_bytes_in_flight = max_bytes_in_flight;
+
+        self
+    }
+
+    fn max_chunks_in_flight(&self, chunk_size_estimate: usize) -> usize {
+        let max_chunks_in_flight = 1 + self.prefetch_chunks;
+        if chunk_size_estimate == 0 {
+            return max_chunks_in_flight;
+        }
+
+        max_chunks_in_flight.min((self.max_bytes_in_flight / chunk_size_estimate).max(1))
+    }
+}
+
+impl Default for StreamingPrefetch {
+    fn default() -> Self {
+        Self::sequential()
+    }
+}
+
+/// Predicts the token of the chunk following the chunk of `token`,
+/// by incrementing the `index` field of a record token.
+pub(crate) fn predict_next_token(token: &Token) -> Option<Token> {
+    let IDLValue::Record(fields) = &token.0 else {
+        return None;
+    };
+
+    let index_id = Label::Named("index".to_string()).get_id();
+    let mut has_index = false;
+    let fields = fields
+        .iter()
+        .map(|field| {
+            if field.id.get_id() != index_id {
+                return Some(field.clone());
+            }
+
+            has_index = true;
+            let val = match &field.val {
+                IDLValue::Nat(index) => IDLValue::Nat(candid::Nat(index.0.clone() + 1u32)),
+                IDLValue::Nat64(index) => IDLValue::Nat64(index.checked_add(1)?),
+                IDLValue::Nat32(index) => IDLValue::Nat32(index.checked_add(1)?),
+                _ => return None,
+            };
+
+            Some(IDLField {
+                id: field.id.clone(),
+                val,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    has_index.then(|| Token(IDLValue::Record(fields)))
+}
+
+struct PrefetchState<F, Fut> {
+    fetch: F,
+    prefetch: StreamingPrefetch,
+    in_flight: FuturesOrdered<Fut>,
+    in_flight_tokens: VecDeque<Token>,
+    next_token: Option<Token>,
+    chunk_size_estimate: usize,
+}
+
+/// Creates a stream of chunks, starting with the chunk of `token`, by calling `fetch`
+/// with the token of each chunk. `chunk_size_estimate` is used to bound the bytes in flight
+/// until the first chunk has been received.
+pub(crate) fn create_prefetch_stream<F, Fut>(
+    fetch: F,
+    token: Option<Token>,
+    prefetch: StreamingPrefetch,
+    chunk_size_estimate: usize,
+) -> impl Stream<Item = ChunkResult>
+where
+    F: Fn(Token) -> Fut,
+    Fut: Future<Output = ChunkResult>,
+{
+    let state = PrefetchState {
+        fetch,
+        prefetch,
+        in_flight: FuturesOrdered::new(),
+        in_flight_tokens: VecDeque::new(),
+        next_token: token,
+        chunk_size_estimate,
+    };
+
+    stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+
+        let max_chunks_in_flight = state
+            .prefetch
+            .max_chunks_in_flight(state.chunk_size_estimate);
+        while state.in_flight.len() < max_chunks_in_flight {
+            let Some(token) = state.next_token.take() else {
+                break;
+            };
+
+            if state.prefetch.prefetch_chunks > 0 {
+                state.next_token = predict_next_token(&token);
+            }
+            state.in_flight.push_back((state.fetch)(token.clone()));
+            state.in_flight_tokens.push_back(token);
+        }
+
+        let result = state.in_flight.next().await?;
+        state.in_flight_tokens.pop_front();
+
+        let (body, token) = match result {
+            Ok(chunk) => chunk,
+            // errors end the stream
+            Err(e) => return Some((Err(e), None)),
+        };
+        state.chunk_size_estimate = body.len();
+
+        let prediction_matches = match (&token, state.in_flight_tokens.front()) {
+            (Some(token), Some(predicted_token)) => token.0 == predicted_token.0,
+            _ => false,
+        };
+        if !prediction_matches {
+            // discard the prefetched chunks and continue from the returned token
+            state.in_flight = FuturesOrdered::new();
+            state.in_flight_tokens.clear();
+            state.next_token = token.clone();
+        }
+
+        Some((Ok((body, token)), Some(state)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    const CHUNK_COUNT: u64 = 16;
+    const CHUNK_SIZE: usize = 1024;
+
+    fn asset_token(index: u64) -> Token {
+        Token(IDLValue::Record(vec![
+            IDLField {
+                id: Label::Named("key".to_string()),
+                val: IDLValue::Text("/video.mp4".to_string()),
+            },
+            IDLField {
+                id: Label::Named("index".to_string()),
+                val: IDLValue::Nat(candid::Nat::from(index)),
+            },
+        ]))
+    }
+
+    fn token_index(token: &Token) -> u64 {
+        let IDLValue::Record(fields) = &token.0 else {
+            panic!("token is not a record");
+        };
+
+        fields
+            .iter()
+            .find_map(|field| match &field.val {
+                IDLValue::Nat(index) => Some(index.0.to_string().parse().unwrap()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    /// The chunks requested from a fake asset and how many of them were in flight at the same time.
+    #[derive(Default)]
+    struct FetchLog {
+        requested: Mutex<Vec<u64>>,
+        in_flight: AtomicUsize,
+        peak_in_flight: AtomicUsize,
+    }
+
+    /// Counts a fetch as in flight until it completes or is discarded.
+    struct InFlight(Arc<FetchLog>);
+
+    impl InFlight {
+        fn start(log: Arc<FetchLog>) -> Self {
+            let in_flight = log.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            log.peak_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+
+            Self(log)
+        }
+    }
+
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Fetches chunks of a fake asset with `CHUNK_COUNT` chunks after `latency`,
+    /// recording the requested indices and the number of chunks in flight.
+    fn fake_asset(
+        latency: Duration,
+        log: Arc<FetchLog>,
+    ) -> impl Fn(Token) -> BoxFuture<'static, ChunkResult> {
+        move |token| {
+            let log = log.clone();
+
+            Box::pin(async move {
+                let index = token_index(&token);
+                log.requested.lock().unwrap().push(index);
+                let _in_flight = InFlight::start(log);
+                tokio::time::sleep(latency).await;
+
+                if index >= CHUNK_COUNT {
+                    return Err(AgentError::MessageError("out of range".to_string()));
+                }
+
+                let next_token = (index + 1 < CHUNK_COUNT).then(|| asset_token(index + 1));
+                Ok((vec![index as u8; CHUNK_SIZE], next_token))
+            })
+        }
+    }
+
+    async fn collect_chunks(prefetch: StreamingPrefetch, log: Arc<FetchLog>) -> Vec<u8> {
+        create_prefetch_stream(
+            fake_asset(Duration::from_millis(10), log),
+            Some(asset_token(1)),
+            prefetch,
+            CHUNK_SIZE,
+        )
+        .map(|chunk| chunk.unwrap().0)
+        .concat()
+        .await
+    }
+
+    fn expected_body() -> Vec<u8> {
+        (1..CHUNK_COUNT)
+            .flat_map(|index| vec![index as u8; CHUNK_SIZE])
+            .collect()
+    }
+
+    #[test]
+    fn test_predict_next_token() {
+        assert_eq!(
+            predict_next_token(&asset_token(1)).unwrap().0,
+            asset_token(2).0
+        );
+        assert!(predict_next_token(&Token(IDLValue::Text("a".to_string()))).is_none());
+        assert!(predict_next_token(&Token(IDLValue::Record(vec![IDLField {
+            id: Label::Named("key".to_string()),
+            val: IDLValue::Text("/index.html".to_string()),
+        }])))
+        .is_none());
+        assert!(predict_next_token(&Token(IDLValue::Record(vec![IDLField {
+            id: Label::Named("index".to_string()),
+            val: IDLValue::Nat64(u64::MAX),
+        }])))
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sequential_stream() {
+        let log = Arc::new(FetchLog::default());
+
+        let body = collect_chunks(StreamingPrefetch::sequential(), log.clone()).await;
+
+        assert_eq!(body, expected_body());
+        assert_eq!(
+            *log.requested.lock().unwrap(),
+            (1..CHUNK_COUNT).collect::<Vec<_>>()
+        );
+        assert_eq!(log.peak_in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_stream() {
+        let log = Arc::new(FetchLog::default());
+
+        let body = collect_chunks(StreamingPrefetch::new(4), log.clone()).await;
+
+        assert_eq!(body, expected_body());
+        // the chunk that is needed and the 4 chunks after it are fetched concurrently
+        assert_eq!(log.peak_in_flight.load(Ordering::SeqCst), 5);
+        // every chunk is fetched once, speculative requests past the end of the asset are discarded
+        let mut requested = log.requested.lock().unwrap().clone();
+        requested.retain(|index| *index < CHUNK_COUNT);
+        requested.sort_unstable();
+        assert_eq!(requested, (1..CHUNK_COUNT).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_stream_respects_bytes_in_flight() {
+        let log = Arc::new(FetchLog::default());
+
+        let body = collect_chunks(
+            StreamingPrefetch::new(4).with_max_bytes_in_flight(CHUNK_SIZE),
+            log.clone(),
+        )
+        .await;
+
+        assert_eq!(body, expected_body());
+        assert_eq!(
+            *log.requested.lock().unwrap(),
+            (1..CHUNK_COUNT).collect::<Vec<_>>()
+        );
+        assert_eq!(log.peak_in_flight.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_stream_falls_back_on_misprediction() {
+        let log = Arc::new(FetchLog::default());
+        let fetch = fake_asset(Duration::ZERO, log);
+
+        // the canister skips every other chunk, so every prediction is wrong
+        let chunks = create_prefetch_stream(
+            |token: Token| {
+                let fetch = fetch(token);
+                async move {
+                    fetch.await.map(|(body, token)| {
+                        let token = token
+                            .map(|token| token_index(&token) + 1)
+                            .filter(|index| *index < CHUNK_COUNT)
+                            .map(asset_token);
+
+                        (body, token)
+                    })
+                }
+            },
+            Some(asset_token(1)),
+            StreamingPrefetch::new(2),
+            CHUNK_SIZE,
+        )
+        .map(|chunk| chunk.unwrap().0[0])
+        .collect::<Vec<_>>()
+        .await;
+
+        assert_eq!(chunks, vec![1, 3, 5, 7, 9, 11, 13, 15]);
+    }
+}
@@ -0,0 +1,177 @@
+use super::{leb128, now_nanos, TestRootKey};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use candid::Principal;
+use ic_certification::{fork, labeled, leaf};
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultFullCelExpression, DefaultResponseOnlyCelExpression,
+    HttpCertification, HttpCertificationPath, HttpCertificationTree, HttpCertificationTreeEntry,
+    HttpRequest, HttpResponse,
+};
+use sha2::{Digest, Sha256};
+
+const CERTIFICATE_HEADER_NAME: &str = "IC-Certificate";
+
+const CERTIFICATE_EXPRESSION_HEADER_NAME: &str = "IC-CertificateExpression";
+
+/// How a [CertificationFixture] certifies a response.
+#[derive(Debug, Clone)]
+pub enum FixtureCertification<'a> {
+    /// Response verification v1, which certifies the body of the response for the path of the request.
+    V1,
+
+    /// Response verification v2, with the canister certifiably skipping verification.
+    Skip,
+
+    /// Response verification v2, certifying the response only.
+    ResponseOnly(DefaultResponseOnlyCelExpression<'a>),
+
+    /// Response verification v2, certifying both the request and the response.
+    Full(DefaultFullCelExpression<'a>),
+}
+
+/// Produces certified responses as a canister would, signed with a [TestRootKey],
+/// so that response verification can be tested without a replica.
+#[derive(Clone)]
+pub struct CertificationFixture {
+    root_key: TestRootKey,
+    canister_id: Principal,
+    time: Option<u64>,
+}
+
+impl CertificationFixture {
+    pub fn new(root_key: TestRootKey, canister_id: Principal) -> Self {
+        Self {
+            root_key,
+            canister_id,
+            time: None,
+        }
+    }
+
+    /// Sets the time of certificates in nanoseconds since the unix epoch, instead of the current time.
+    /// This allows testing the rejection of outdated certificates.
+    pub fn with_time(mut self, time: u64) -> Self {
+        self.time = Some(time);
+
+        self
+    }
+
+    /// Returns a CBOR-encoded certificate for `certified_data`, the data that the canister certified.
+    pub fn certificate(&self, certified_data: &[u8]) -> Vec<u8> {
+        let time = self.time.unwrap_or_else(now_nanos);
+        let tree = fork(
+            labeled(
+                "canister",
+                labeled(
+                    self.canister_id.as_slice(),
+                    labeled("certified_data", leaf(certified_data)),
+                ),
+            ),
+            labeled("time", leaf(leb128(time))),
+        );
+
+        self.root_key.certify(tree)
+    }
+
+    /// Certifies `response` as the response to `request`, returning the response with the
+    /// `IC-Certificate` header and, for response verification v2, the `IC-CertificateExpression` header.
+    pub fn certify(
+        &self,
+        request: &HttpRequest,
+        mut response: HttpResponse,
+        certification: FixtureCertification,
+    ) -> HttpResponse {
+        let path = request_path(&request.url);
+
+        let certificate_header = match certification {
+            FixtureCertification::V1 => self.v1_certificate_header(path, &response.body),
+            FixtureCertification::Skip => {
+                let cel_expr = DefaultCelBuilder::skip_certification().to_string();
+                response
+                    .headers
+                    .push((CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(), cel_expr));
+
+                self.v2_certificate_header(path, HttpCertification::skip())
+            }
+            FixtureCertification::ResponseOnly(cel_expr) => {
+                response.headers.push((
+                    CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+                    cel_expr.to_string(),
+                ));
+                let certification = HttpCertification::response_only(&cel_expr, &response, None)
+                    .expect("the response can be certified");
+
+                self.v2_certificate_header(path, certification)
+            }
+            FixtureCertification::Full(cel_expr) => {
+                response.headers.push((
+                    CERTIFICATE_EXPRESSION_HEADER_NAME.to_string(),
+                    cel_expr.to_string(),
+                ));
+                let certification = HttpCertification::full(&cel_expr, request, &response, None)
+                    .expect("the request and response can be certified");
+
+                self.v2_certificate_header(path, certification)
+            }
+        };
+
+        response
+            .headers
+            .push((CERTIFICATE_HEADER_NAME.to_string(), certificate_header));
+
+        response
+    }
+
+    fn v1_certificate_header(&self, path: &str, body: &[u8]) -> String {
+        let tree = labeled(
+            "http_assets",
+            labeled(path, leaf(Sha256::digest(body).to_vec())),
+        );
+        let certificate = self.certificate(&tree.digest());
+
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(cbor_encode(&tree))
+        )
+    }
+
+    fn v2_certificate_header(&self, path: &str, certification: HttpCertification) -> String {
+        let certification_path = HttpCertificationPath::exact(path);
+        let entry = HttpCertificationTreeEntry::new(&certification_path, &certification);
+
+        let mut tree = HttpCertificationTree::default();
+        tree.insert(&entry);
+        let witness = tree
+            .witness(&entry, path)
+            .expect("the entry was inserted into the tree");
+        let certificate = self.certificate(&tree.root_hash());
+
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            BASE64.encode(certificate),
+            BASE64.encode(cbor_encode(&witness)),
+            BASE64.encode(cbor_encode(&certification_path.to_expr_path()))
+        )
+    }
+}
+
+/// Returns the path of `url`, without the query string.
+fn request_path(url: &str) -> &str {
+    url.split_once('?').map_or(url, |(path, _)| path)
+}
+
+fn cbor_encode(value: &impl serde::Serialize) -> Vec<u8> {
+    serde_cbor::to_vec(value).expect("the value can always be encoded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("/index.html"), "/index.html");
+        assert_eq!(request_path("/index.html?a=b"), "/index.html");
+        assert_eq!(request_path("/"), "/");
+    }
+}
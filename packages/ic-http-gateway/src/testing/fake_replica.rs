@@ -0,0 +1,546 @@
+use super::{leb128, now_nanos, representation_independent_hash, TestRootKey};
+use candid::{CandidType, Principal};
+use ed25519_consensus::SigningKey;
+use ic_agent::{
+    agent::{AgentFuture, RejectCode, Transport},
+    Agent, AgentError,
+};
+use ic_certification::{fork, labeled, leaf, HashTree};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// The domain separator of the message that replica nodes sign query responses with.
+const QUERY_RESPONSE_DOMAIN_SEPARATOR: &[u8] = b"\x0Bic-response";
+
+/// The DER prefix of an Ed25519 public key, as used for the public keys of replica nodes.
+const ED25519_DER_PREFIX: &[u8; 12] = b"\x30\x2a\x30\x05\x06\x03\x2b\x65\x70\x03\x21\x00";
+
+const NODE_KEY_SEED: &[u8] = b"ic-http-gateway test node key";
+
+/// A scripted reply of the fake replica to a query or update call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeReply {
+    /// The canister replied with the given Candid-encoded bytes.
+    Reply(Vec<u8>),
+
+    /// The call was rejected.
+    Reject {
+        reject_code: RejectCode,
+        reject_message: String,
+    },
+
+    /// The replica could not be reached.
+    TransportError(String),
+}
+
+impl FakeReply {
+    /// Replies with the Candid encoding of `value`, for example an `HttpResponse`.
+    pub fn candid(value: &impl CandidType) -> Self {
+        FakeReply::Reply(candid::encode_one(value).expect("the reply can be Candid-encoded"))
+    }
+
+    /// Rejects the call with `reject_code` and `reject_message`.
+    pub fn reject(reject_code: RejectCode, reject_message: impl Into<String>) -> Self {
+        FakeReply::Reject {
+            reject_code,
+            reject_message: reject_message.into(),
+        }
+    }
+}
+
+/// A request that was received by the fake replica.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeRequest {
+    /// The request type, `query`, `call` or `read_state`.
+    pub request_type: String,
+
+    /// The principal that signed the request.
+    pub sender: Option<Principal>,
+
+    /// The canister that was called, for query and update calls.
+    pub canister_id: Option<Principal>,
+
+    /// The method that was called, for query and update calls.
+    pub method_name: Option<String>,
+
+    /// The Candid-encoded argument of query and update calls.
+    pub arg: Vec<u8>,
+}
+
+/// An in-process replica that implements the agent's [Transport], so that the protocol handler
+/// can be tested without PocketIC.
+///
+/// Replies to `http_request`, `http_request_update` and streaming callbacks are scripted per
+/// canister and method with [push_query_reply](FakeReplica::push_query_reply) and
+/// [push_update_reply](FakeReplica::push_update_reply), and are returned in the order they were pushed.
+/// Calls without a scripted reply are rejected with `CanisterError`.
+///
+/// Update call replies are certified with the fake replica's [TestRootKey]. The status of an update
+/// call is stored under the request id computed from its content, so that concurrent calls
+/// get their own replies.
+///
+/// Query responses are signed by the only node of the fake replica's subnet, whose public key
+/// is served along with the subnet's canister ranges by `read_state`, so that query signatures
+/// can be verified.
+#[derive(Clone, Default)]
+pub struct FakeReplica {
+    root_key: TestRootKey,
+    state: Arc<Mutex<FakeReplicaState>>,
+}
+
+#[derive(Default)]
+struct FakeReplicaState {
+    query_replies: HashMap<(Principal, String), VecDeque<FakeReply>>,
+    update_replies: HashMap<(Principal, String), VecDeque<FakeReply>>,
+    call_statuses: HashMap<Vec<u8>, FakeReply>,
+    requests: Vec<FakeRequest>,
+}
+
+impl FakeReplica {
+    /// Creates a fake replica that certifies replies with `root_key`.
+    pub fn new(root_key: TestRootKey) -> Self {
+        Self {
+            root_key,
+            state: Arc::default(),
+        }
+    }
+
+    /// Returns the root key that replies are certified with.
+    pub fn root_key(&self) -> &TestRootKey {
+        &self.root_key
+    }
+
+    /// Scripts the reply to the next query call of `method_name` on `canister_id`.
+    pub fn push_query_reply(&self, canister_id: Principal, method_name: &str, reply: FakeReply) {
+        self.lock()
+            .query_replies
+            .entry((canister_id, method_name.to_string()))
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Scripts the reply to the next update call of `method_name` on `canister_id`.
+    pub fn push_update_reply(&self, canister_id: Principal, method_name: &str, reply: FakeReply) {
+        self.lock()
+            .update_replies
+            .entry((canister_id, method_name.to_string()))
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Returns the requests received so far, in the order they were received.
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Creates an agent that sends its requests to this fake replica and trusts its root key.
+    /// The agent does not verify query signatures itself, so that only the gateway fetches
+    /// the node keys of the subnet when it is configured to verify query signatures.
+    pub fn agent(&self) -> Agent {
+        let agent = Agent::builder()
+            .with_transport(self.clone())
+            .with_verify_query_signatures(false)
+            .build()
+            .expect("an agent with a transport can always be built");
+        agent.set_root_key(self.root_key.public_key_der());
+
+        agent
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, FakeReplicaState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records the request in `envelope` and returns its content.
+    fn receive(
+        &self,
+        envelope: &[u8],
+    ) -> Result<(FakeRequest, BTreeMap<Value, Value>), AgentError> {
+        let content = decode_content(envelope)?;
+        let request = FakeRequest {
+            request_type: text_field(&content, "request_type").unwrap_or_default(),
+            sender: bytes_field(&content, "sender").map(|sender| Principal::from_slice(&sender)),
+            canister_id: bytes_field(&content, "canister_id")
+                .map(|canister_id| Principal::from_slice(&canister_id)),
+            method_name: text_field(&content, "method_name"),
+            arg: bytes_field(&content, "arg").unwrap_or_default(),
+        };
+        self.lock().requests.push(request.clone());
+
+        Ok((request, content))
+    }
+
+    fn handle_query(&self, envelope: &[u8]) -> Result<Vec<u8>, AgentError> {
+        let (request, content) = self.receive(envelope)?;
+        let reply = next_reply(&mut self.lock().query_replies, &request);
+
+        let mut response = match reply {
+            FakeReply::Reply(arg) => vec![
+                ("status", Value::Text("replied".to_string())),
+                ("reply", cbor_map([("arg", Value::Bytes(arg))])),
+            ],
+            FakeReply::Reject {
+                reject_code,
+                reject_message,
+            } => vec![
+                ("status", Value::Text("rejected".to_string())),
+                ("reject_code", Value::Integer(reject_code as u8 as i128)),
+                ("reject_message", Value::Text(reject_message)),
+            ],
+            FakeReply::TransportError(message) => return Err(transport_error(message)),
+        };
+        let signature = sign_query_response(&response, request_id(&content));
+        response.push(("signatures", Value::Array(vec![signature])));
+
+        Ok(cbor_encode(&cbor_map(response)))
+    }
+
+    fn handle_call(&self, envelope: &[u8]) -> Result<(), AgentError> {
+        let (request, content) = self.receive(envelope)?;
+        let mut state = self.lock();
+
+        match next_reply(&mut state.update_replies, &request) {
+            FakeReply::TransportError(message) => Err(transport_error(message)),
+            reply => {
+                state.call_statuses.insert(request_id(&content), reply);
+
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: &[u8],
+    ) -> Result<Vec<u8>, AgentError> {
+        let (_, content) = self.receive(envelope)?;
+
+        let state_tree = if let Some(request_id) = request_status_id(&content) {
+            let status = self.lock().call_statuses.get(&request_id).cloned();
+
+            labeled(
+                "request_status",
+                labeled(request_id, request_status_tree(status)),
+            )
+        } else if has_subnet_path(&content) {
+            let subnet_id = Principal::self_authenticating(self.root_key.public_key_der());
+
+            labeled(
+                "subnet",
+                labeled(
+                    subnet_id.as_slice().to_vec(),
+                    self.subnet_tree(effective_canister_id),
+                ),
+            )
+        } else {
+            return Err(transport_error(
+                "the fake replica only serves the status of update calls and its subnet",
+            ));
+        };
+
+        let tree = fork(state_tree, labeled("time", leaf(leb128(now_nanos()))));
+        let certificate = self.root_key.certify(tree);
+
+        Ok(cbor_encode(&cbor_map([(
+            "certificate",
+            Value::Bytes(certificate),
+        )])))
+    }
+
+    /// Returns the state of the fake replica's subnet, which hosts `canister_id` and has a single node,
+    /// with its labels in sorted order. The subnet is the root subnet, so its id is derived from
+    /// the root key and certificates are not delegated.
+    fn subnet_tree(&self, canister_id: Principal) -> HashTree {
+        let canister_id = Value::Bytes(canister_id.as_slice().to_vec());
+        let canister_ranges =
+            Value::Array(vec![Value::Array(vec![canister_id.clone(), canister_id])]);
+
+        fork(
+            fork(
+                labeled("canister_ranges", leaf(cbor_encode(&canister_ranges))),
+                labeled(
+                    "node",
+                    labeled(
+                        node_id().as_slice().to_vec(),
+                        labeled("public_key", leaf(node_public_key_der())),
+                    ),
+                ),
+            ),
+            labeled("public_key", leaf(self.root_key.public_key_der())),
+        )
+    }
+
+    fn handle_status(&self) -> Vec<u8> {
+        cbor_encode(&cbor_map([
+            ("ic_api_version", Value::Text("0.18.0".to_string())),
+            ("root_key", Value::Bytes(self.root_key.public_key_der())),
+            ("replica_health_status", Value::Text("healthy".to_string())),
+        ]))
+    }
+}
+
+impl Transport for FakeReplica {
+    fn call(&self, _effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<()> {
+        Box::pin(async move { self.handle_call(&envelope) })
+    }
+
+    fn read_state(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> AgentFuture<Vec<u8>> {
+        Box::pin(async move { self.handle_read_state(effective_canister_id, &envelope) })
+    }
+
+    fn read_subnet_state(&self, _subnet_id: Principal, _envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        Box::pin(async {
+            Err(transport_error(
+                "the fake replica does not serve the state of subnets",
+            ))
+        })
+    }
+
+    fn query(&self, _effective_canister_id: Principal, envelope: Vec<u8>) -> AgentFuture<Vec<u8>> {
+        Box::pin(async move { self.handle_query(&envelope) })
+    }
+
+    fn status(&self) -> AgentFuture<Vec<u8>> {
+        Box::pin(async move { Ok(self.handle_status()) })
+    }
+}
+
+/// Pops the scripted reply to `request`, or rejects it if there is none.
+fn next_reply(
+    replies: &mut HashMap<(Principal, String), VecDeque<FakeReply>>,
+    request: &FakeRequest,
+) -> FakeReply {
+    let (Some(canister_id), Some(method_name)) = (request.canister_id, &request.method_name) else {
+        return FakeReply::reject(RejectCode::DestinationInvalid, "no canister was called");
+    };
+
+    replies
+        .get_mut(&(canister_id, method_name.clone()))
+        .and_then(VecDeque::pop_front)
+        .unwrap_or_else(|| {
+            FakeReply::reject(
+                RejectCode::CanisterError,
+                format!(
+                    "no reply scripted for {} on canister {}",
+                    method_name, canister_id
+                ),
+            )
+        })
+}
+
+/// Returns the status subtree of an update call, with its labels in sorted order.
+fn request_status_tree(status: Option<FakeReply>) -> HashTree {
+    match status {
+        Some(FakeReply::Reply(arg)) => fork(
+            labeled("reply", leaf(arg)),
+            labeled("status", leaf("replied")),
+        ),
+        Some(FakeReply::Reject {
+            reject_code,
+            reject_message,
+        }) => fork(
+            fork(
+                labeled("reject_code", leaf(leb128(reject_code as u8 as u64))),
+                labeled("reject_message", leaf(reject_message)),
+            ),
+            labeled("status", leaf("rejected")),
+        ),
+        _ => labeled("status", leaf("processing")),
+    }
+}
+
+/// Signs a query response with the key of the fake replica's node, along with the request id
+/// of the query call and the current time, and returns the signature as included in the response.
+fn sign_query_response(response: &[(&str, Value)], request_id: Vec<u8>) -> Value {
+    let timestamp = now_nanos();
+    let signable = cbor_map(response.iter().cloned().chain([
+        ("request_id", Value::Bytes(request_id)),
+        ("timestamp", Value::Integer(timestamp.into())),
+    ]));
+    let message = [
+        QUERY_RESPONSE_DOMAIN_SEPARATOR,
+        representation_independent_hash(&signable).as_slice(),
+    ]
+    .concat();
+
+    cbor_map([
+        ("timestamp", Value::Integer(timestamp.into())),
+        (
+            "signature",
+            Value::Bytes(node_key().sign(&message).to_bytes().to_vec()),
+        ),
+        ("identity", Value::Bytes(node_id().as_slice().to_vec())),
+    ])
+}
+
+fn node_key() -> SigningKey {
+    let seed: [u8; 32] = Sha256::digest(NODE_KEY_SEED).into();
+
+    SigningKey::from(seed)
+}
+
+fn node_public_key_der() -> Vec<u8> {
+    [
+        ED25519_DER_PREFIX.as_slice(),
+        node_key().verification_key().to_bytes().as_slice(),
+    ]
+    .concat()
+}
+
+fn node_id() -> Principal {
+    Principal::self_authenticating(node_public_key_der())
+}
+
+/// Returns the request id of a request with the given content.
+fn request_id(content: &BTreeMap<Value, Value>) -> Vec<u8> {
+    representation_independent_hash(&Value::Map(content.clone())).to_vec()
+}
+
+/// Returns the request id of the first `request_status` path in a `read_state` request.
+fn request_status_id(content: &BTreeMap<Value, Value>) -> Option<Vec<u8>> {
+    paths(content).find_map(|labels| match labels {
+        [Value::Bytes(label), Value::Bytes(request_id), ..] if label == b"request_status" => {
+            Some(request_id.clone())
+        }
+        _ => None,
+    })
+}
+
+/// Returns whether a `read_state` request asks for the state of subnets.
+fn has_subnet_path(content: &BTreeMap<Value, Value>) -> bool {
+    paths(content).any(|labels| matches!(labels, [Value::Bytes(label), ..] if label == b"subnet"))
+}
+
+/// Returns the labels of the paths of a `read_state` request.
+fn paths(content: &BTreeMap<Value, Value>) -> impl Iterator<Item = &[Value]> {
+    let paths = match field(content, "paths") {
+        Some(Value::Array(paths)) => paths.as_slice(),
+        _ => &[],
+    };
+
+    paths.iter().filter_map(|path| match path {
+        Value::Array(labels) => Some(labels.as_slice()),
+        _ => None,
+    })
+}
+
+fn decode_content(envelope: &[u8]) -> Result<BTreeMap<Value, Value>, AgentError> {
+    let envelope: Value = serde_cbor::from_slice(envelope)
+        .map_err(|e| transport_error(format!("invalid envelope: {}", e)))?;
+
+    match envelope {
+        Value::Map(mut envelope) => match envelope.remove(&Value::Text("content".to_string())) {
+            Some(Value::Map(content)) => Ok(content),
+            _ => Err(transport_error("the envelope has no content")),
+        },
+        _ => Err(transport_error("the envelope is not a map")),
+    }
+}
+
+fn field<'a>(content: &'a BTreeMap<Value, Value>, name: &str) -> Option<&'a Value> {
+    content.get(&Value::Text(name.to_string()))
+}
+
+fn bytes_field(content: &BTreeMap<Value, Value>, name: &str) -> Option<Vec<u8>> {
+    match field(content, name) {
+        Some(Value::Bytes(bytes)) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+fn text_field(content: &BTreeMap<Value, Value>, name: &str) -> Option<String> {
+    match field(content, name) {
+        Some(Value::Text(text)) => Some(text.clone()),
+        _ => None,
+    }
+}
+
+fn cbor_map<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (Value::Text(key.to_string()), value))
+            .collect(),
+    )
+}
+
+fn cbor_encode(value: &Value) -> Vec<u8> {
+    serde_cbor::to_vec(value).expect("a CBOR value can always be encoded")
+}
+
+fn transport_error(message: impl Into<String>) -> AgentError {
+    AgentError::TransportError(message.into().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_call_statuses_are_keyed_by_request_id() {
+        let canister_id = Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap();
+        let replica = FakeReplica::default();
+        replica.push_update_reply(canister_id, "update", FakeReply::Reply(vec![1]));
+        replica.push_update_reply(canister_id, "update", FakeReply::Reply(vec![2]));
+        let agent = replica.agent();
+
+        let first = agent
+            .update(&canister_id, "update")
+            .with_arg(vec![1])
+            .call()
+            .await
+            .unwrap();
+        let second = agent
+            .update(&canister_id, "update")
+            .with_arg(vec![2])
+            .call()
+            .await
+            .unwrap();
+
+        // the statuses are read in the opposite order of the calls
+        assert_eq!(agent.wait(second, canister_id).await.unwrap(), vec![2]);
+        assert_eq!(agent.wait(first, canister_id).await.unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_next_reply_pops_scripted_replies_in_order() {
+        let canister_id = Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap();
+        let replica = FakeReplica::default();
+        replica.push_query_reply(canister_id, "http_request", FakeReply::Reply(vec![1]));
+        replica.push_query_reply(canister_id, "http_request", FakeReply::Reply(vec![2]));
+
+        let request = FakeRequest {
+            request_type: "query".to_string(),
+            sender: None,
+            canister_id: Some(canister_id),
+            method_name: Some("http_request".to_string()),
+            arg: vec![],
+        };
+        let mut state = replica.lock();
+
+        assert_eq!(
+            next_reply(&mut state.query_replies, &request),
+            FakeReply::Reply(vec![1])
+        );
+        assert_eq!(
+            next_reply(&mut state.query_replies, &request),
+            FakeReply::Reply(vec![2])
+        );
+        assert!(matches!(
+            next_reply(&mut state.query_replies, &request),
+            FakeReply::Reject {
+                reject_code: RejectCode::CanisterError,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,40 @@
+use crate::{
+    protocol, CanisterRequest, CanisterResponse, HttpGatewayResult, InvalidHeaderValueAction,
+    RequestHeaderPolicy, UrlNormalization,
+};
+use http::{HeaderValue, StatusCode};
+use ic_http_certification::HttpRequest;
+
+/// Converts `request` into the request that is sent to the canister, using the default request header policy.
+pub fn convert_request(
+    request: CanisterRequest,
+    url_normalization: UrlNormalization,
+    invalid_header_value_action: InvalidHeaderValueAction,
+) -> HttpGatewayResult<HttpRequest> {
+    protocol::convert_request(
+        &request,
+        &RequestHeaderPolicy::default(),
+        url_normalization,
+        invalid_header_value_action,
+        &mut vec![],
+    )
+}
+
+/// Converts the value of a request header into the value that is sent to the canister.
+pub fn convert_request_header_value(
+    header_name: &str,
+    header_value: &HeaderValue,
+    action: InvalidHeaderValueAction,
+) -> HttpGatewayResult<Option<String>> {
+    crate::convert_request_header_value(header_name, header_value, action, &mut vec![])
+}
+
+/// Rewrites the value of an `accept-encoding` header as it is sent to the canister.
+pub fn add_identity_encoding(accept_encoding: &str) -> String {
+    protocol::add_identity_encoding(accept_encoding)
+}
+
+/// Creates the response that the gateway answers a failed request with.
+pub fn create_err_response(status_code: StatusCode, msg: &str) -> CanisterResponse {
+    protocol::create_err_response(status_code, msg)
+}
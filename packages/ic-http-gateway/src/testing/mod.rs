@@ -0,0 +1,126 @@
+//! Test support for exercising the gateway without a replica.
+//!
+//! Only available with the `testing` feature.
+
+mod test_root_key;
+pub use test_root_key::*;
+
+mod fake_replica;
+pub use fake_replica::*;
+
+mod certification_fixture;
+pub use certification_fixture::*;
+
+/// Entry points into the parts of the gateway that handle untrusted input, for fuzzing.
+pub mod fuzzing;
+
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Encodes `value` as unsigned LEB128, as used for the `time` leaf of certificates.
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Computes the representation-independent hash of `value`, which is how the request id of a request
+/// is computed from its content. Only the values that occur in requests are supported.
+fn representation_independent_hash(value: &Value) -> [u8; 32] {
+    match value {
+        Value::Integer(integer) => Sha256::digest(leb128(*integer as u64)).into(),
+        Value::Bytes(bytes) => Sha256::digest(bytes).into(),
+        Value::Text(text) => Sha256::digest(text.as_bytes()).into(),
+        Value::Array(values) => Sha256::digest(
+            values
+                .iter()
+                .flat_map(representation_independent_hash)
+                .collect::<Vec<_>>(),
+        )
+        .into(),
+        Value::Map(entries) => {
+            let mut entry_hashes = entries
+                .iter()
+                .map(|(key, value)| {
+                    [
+                        representation_independent_hash(key),
+                        representation_independent_hash(value),
+                    ]
+                    .concat()
+                })
+                .collect::<Vec<_>>();
+            entry_hashes.sort();
+
+            Sha256::digest(entry_hashes.concat()).into()
+        }
+        _ => panic!("unsupported value in request content: {:?}", value),
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("the system time is after the unix epoch")
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_representation_independent_hash_matches_request_ids() {
+        #[derive(serde::Serialize)]
+        struct Content {
+            request_type: &'static str,
+            sender: candid::Principal,
+            ingress_expiry: u64,
+            paths: Vec<Vec<candid::Principal>>,
+        }
+
+        let sender = candid::Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap();
+        let content = Content {
+            request_type: "read_state",
+            sender,
+            ingress_expiry: 1_685_570_400_000_000_000,
+            paths: vec![vec![sender]],
+        };
+        let value = Value::Map(
+            [
+                ("request_type", Value::Text("read_state".to_string())),
+                ("sender", Value::Bytes(sender.as_slice().to_vec())),
+                ("ingress_expiry", Value::Integer(1_685_570_400_000_000_000)),
+                (
+                    "paths",
+                    Value::Array(vec![Value::Array(vec![Value::Bytes(
+                        sender.as_slice().to_vec(),
+                    )])]),
+                ),
+            ]
+            .into_iter()
+            .map(|(key, value)| (Value::Text(key.to_string()), value))
+            .collect(),
+        );
+
+        assert_eq!(
+            representation_independent_hash(&value).as_slice(),
+            ic_agent::to_request_id(&content).unwrap().as_slice()
+        );
+    }
+
+    #[test]
+    fn test_leb128() {
+        assert_eq!(leb128(0), vec![0]);
+        assert_eq!(leb128(127), vec![127]);
+        assert_eq!(leb128(624485), vec![0xe5, 0x8e, 0x26]);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::RootKey;
+use ic_certification::{Certificate, HashTree};
+use ic_verify_bls_signature::PrivateKey;
+use sha2::{Digest, Sha256};
+
+/// The domain separator of the message that the root of the state tree is signed with.
+const STATE_ROOT_DOMAIN_SEPARATOR: &[u8] = b"\x0Dic-state-root";
+
+const DEFAULT_SEED: &[u8] = b"ic-http-gateway test root key";
+
+/// A BLS12-381 key pair that stands in for the root key of the Internet Computer,
+/// so that tests can sign their own certificates.
+#[derive(Clone)]
+pub struct TestRootKey {
+    private_key: PrivateKey,
+}
+
+impl TestRootKey {
+    /// Derives a key pair from `seed`. The same seed always results in the same key pair.
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let mut scalar: [u8; 32] = Sha256::digest(seed).into();
+        // clear the top bits, so that the big-endian scalar is smaller than the group order
+        scalar[0] &= 0x3f;
+
+        Self {
+            private_key: PrivateKey::deserialize(&scalar)
+                .expect("a scalar below the group order is a valid private key"),
+        }
+    }
+
+    /// Returns the raw, 96 byte public key.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.private_key.public_key().serialize().to_vec()
+    }
+
+    /// Returns the DER-encoded public key, as returned by the `status` endpoint of a replica.
+    pub fn public_key_der(&self) -> Vec<u8> {
+        match self.root_key() {
+            RootKey::Pinned(der) => der,
+            _ => unreachable!("a public key is always pinned"),
+        }
+    }
+
+    /// Returns the public key as a root key that the gateway can be configured with.
+    pub fn root_key(&self) -> RootKey {
+        RootKey::from_bytes(&self.public_key()).expect("a public key has 96 bytes")
+    }
+
+    /// Signs the root hash of `tree` and returns the CBOR-encoded certificate.
+    pub fn certify(&self, tree: HashTree) -> Vec<u8> {
+        let message = [STATE_ROOT_DOMAIN_SEPARATOR, tree.digest().as_slice()].concat();
+        let certificate = Certificate {
+            tree,
+            signature: self.private_key.sign(&message).serialize().to_vec(),
+            delegation: None,
+        };
+
+        serde_cbor::to_vec(&certificate).expect("a certificate can always be encoded")
+    }
+}
+
+impl Default for TestRootKey {
+    fn default() -> Self {
+        Self::from_seed(DEFAULT_SEED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_certification::{labeled, leaf};
+    use ic_verify_bls_signature::verify_bls_signature;
+
+    #[test]
+    fn test_from_seed_is_deterministic() {
+        assert_eq!(
+            TestRootKey::from_seed(b"a").public_key(),
+            TestRootKey::from_seed(b"a").public_key()
+        );
+        assert_ne!(
+            TestRootKey::from_seed(b"a").public_key(),
+            TestRootKey::from_seed(b"b").public_key()
+        );
+    }
+
+    #[test]
+    fn test_public_key_der() {
+        let root_key = TestRootKey::default();
+
+        assert_eq!(
+            RootKey::from_der(&root_key.public_key_der()).unwrap(),
+            root_key.root_key()
+        );
+    }
+
+    #[test]
+    fn test_certify() {
+        let root_key = TestRootKey::default();
+        let tree = labeled(b"time", leaf(vec![0]));
+        let digest = tree.digest();
+
+        let certificate: Certificate = serde_cbor::from_slice(&root_key.certify(tree)).unwrap();
+        let message = [STATE_ROOT_DOMAIN_SEPARATOR, digest.as_slice()].concat();
+
+        assert!(
+            verify_bls_signature(&certificate.signature, &message, &root_key.public_key()).is_ok()
+        );
+    }
+}
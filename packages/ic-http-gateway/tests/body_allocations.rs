@@ -0,0 +1,201 @@
+use candid::{CandidType, Principal};
+use http::Request;
+use http_body_util::BodyExt;
+use ic_agent::Agent;
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseCertification, HttpRequest, HttpResponse,
+};
+use ic_http_gateway::{
+    get_body_and_streaming_body,
+    testing::{CertificationFixture, FakeReplica, FakeReply, FixtureCertification, TestRootKey},
+    AgentResponseAny, HttpGatewayClient, HttpGatewayRequestArgs, StreamingBodyOptions,
+    StreamingCallbackPolicy, StreamingLimits, StreamingPrefetch,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// Counts the bytes allocated by each thread of the test binary,
+/// so that tests running in parallel do not affect each other's counts.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation(bytes: usize) {
+    // the counter is unavailable while the thread is being torn down
+    let _ = ALLOCATED_BYTES.try_with(|allocated_bytes| {
+        allocated_bytes.set(allocated_bytes.get() + bytes);
+    });
+}
+
+fn allocated_bytes() -> usize {
+    ALLOCATED_BYTES.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const BODY_SIZE: usize = 8 * 1024 * 1024;
+
+fn canister_id() -> Principal {
+    Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+}
+
+#[tokio::test]
+async fn get_body_and_streaming_body_does_not_copy_the_body() {
+    let agent = Agent::builder()
+        .with_url("http://127.0.0.1:4943")
+        .build()
+        .unwrap();
+    let streaming_callback_policy = StreamingCallbackPolicy::default();
+    let response = AgentResponseAny {
+        status_code: 200,
+        headers: vec![],
+        body: vec![42; BODY_SIZE],
+        streaming_strategy: None,
+        upgrade: None,
+    };
+
+    let allocated_bytes_before = allocated_bytes();
+
+    let body = get_body_and_streaming_body(
+        &agent,
+        &canister_id(),
+        response,
+        StreamingBodyOptions {
+            streaming_prefetch: StreamingPrefetch::default(),
+            streaming_limits: StreamingLimits::default(),
+            streaming_callback_policy: &streaming_callback_policy,
+            verify_query_signatures: false,
+            integrity_trailers: false,
+        },
+    )
+    .await
+    .unwrap();
+    let body = body.collect().await.unwrap().to_bytes();
+
+    let allocated_bytes = allocated_bytes() - allocated_bytes_before;
+
+    assert_eq!(body.len(), BODY_SIZE);
+    assert!(body.iter().all(|b| *b == 42));
+    // copying the body even once would allocate at least `BODY_SIZE` bytes
+    assert!(
+        allocated_bytes < BODY_SIZE / 16,
+        "allocated {} bytes for a body of {} bytes",
+        allocated_bytes,
+        BODY_SIZE
+    );
+}
+
+/// The `HttpResponse` type of the HTTP interface of canisters, without a streaming strategy.
+#[derive(CandidType)]
+struct CanisterHttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>,
+}
+
+/// Returns the bytes allocated to serve a certified response with a body of `BODY_SIZE` bytes.
+async fn allocated_bytes_for_certified_response(skip_verification: bool) -> usize {
+    let root_key = TestRootKey::default();
+    let replica = FakeReplica::new(root_key.clone());
+    let certified_response = CertificationFixture::new(root_key, canister_id()).certify(
+        &HttpRequest {
+            method: "GET".to_string(),
+            url: "/".to_string(),
+            headers: vec![],
+            body: vec![],
+        },
+        HttpResponse {
+            status_code: 200,
+            headers: vec![],
+            body: vec![42; BODY_SIZE],
+            upgrade: None,
+        },
+        FixtureCertification::ResponseOnly(
+            DefaultCelBuilder::response_only_certification()
+                .with_response_certification(
+                    DefaultResponseCertification::response_header_exclusions(vec![]),
+                )
+                .build(),
+        ),
+    );
+    replica.push_query_reply(
+        canister_id(),
+        "http_request",
+        FakeReply::candid(&CanisterHttpResponse {
+            status_code: certified_response.status_code,
+            headers: certified_response.headers,
+            body: certified_response.body,
+            upgrade: None,
+        }),
+    );
+    let http_gateway = HttpGatewayClient::builder()
+        .with_agent(replica.agent())
+        .with_root_key(replica.root_key().root_key())
+        .build()
+        .unwrap();
+
+    let allocated_bytes_before = allocated_bytes();
+
+    let mut request = http_gateway.request(HttpGatewayRequestArgs {
+        canister_id: canister_id(),
+        canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+        client_info: None,
+        identity: None,
+    });
+    request.unsafe_set_skip_verification(skip_verification);
+    let response = request.send().await;
+
+    assert_eq!(response.canister_response.status(), 200);
+    assert_eq!(
+        response.metadata.response_verification_version,
+        (!skip_verification).then_some(2)
+    );
+    let body = response
+        .canister_response
+        .into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    assert_eq!(body.len(), BODY_SIZE);
+
+    allocated_bytes() - allocated_bytes_before
+}
+
+#[tokio::test]
+async fn verification_does_not_copy_the_body() {
+    // decoding the reply of the fake replica allocates the body regardless of verification
+    let unverified_allocated_bytes = allocated_bytes_for_certified_response(true).await;
+    let verified_allocated_bytes = allocated_bytes_for_certified_response(false).await;
+
+    // copying the body even once for verification would allocate at least `BODY_SIZE` more bytes
+    assert!(
+        verified_allocated_bytes < unverified_allocated_bytes + BODY_SIZE / 16,
+        "allocated {} bytes with verification and {} bytes without for a body of {} bytes",
+        verified_allocated_bytes,
+        unverified_allocated_bytes,
+        BODY_SIZE
+    );
+}
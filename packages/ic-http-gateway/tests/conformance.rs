@@ -0,0 +1,555 @@
+//! Checks that the gateway behaves as the HTTP Gateway Protocol specification requires,
+//! using the scenarios in `conformance/scenarios.json`.
+//!
+//! Every scenario describes a request, how the canister responds to it and the response that is
+//! expected from the gateway. Scenarios run against an in-process fake replica that is scripted
+//! with the canister behaviour of the scenario. Scenarios that target `pocket_ic` also run against
+//! the example canister in PocketIC, which implements the same behaviour for their URLs.
+
+use candid::{define_function, CandidType, Principal};
+use http::Request;
+use http_body_util::BodyExt;
+use ic_agent::agent::RejectCode;
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseCertification, HttpRequest, HttpResponse,
+};
+use ic_http_gateway::{
+    testing::{CertificationFixture, FakeReplica, FakeReply, FixtureCertification, TestRootKey},
+    EndpointPool, HttpGatewayClient, HttpGatewayClientBuilder, HttpGatewayRequestArgs, RootKey,
+    StreamingLimits,
+};
+use pocket_ic::PocketIcBuilder;
+use serde::{Deserialize, Deserializer};
+
+mod utils;
+
+const SCENARIOS: &str = include_str!("conformance/scenarios.json");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    name: String,
+    section: String,
+    #[serde(default = "default_targets")]
+    targets: Vec<Target>,
+    request: ScenarioRequest,
+    #[serde(default)]
+    client: ScenarioClient,
+    #[serde(default)]
+    canister: ScenarioCanister,
+    expected: Expected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Target {
+    Fake,
+    PocketIc,
+}
+
+fn default_targets() -> Vec<Target> {
+    vec![Target::Fake]
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioRequest {
+    #[serde(default = "default_method")]
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    body: String,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioClient {
+    max_verified_callback_calls: Option<usize>,
+    max_body_bytes: Option<usize>,
+}
+
+/// How the canister responds to `http_request` and `http_request_update`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioCanister {
+    http_request: Option<CanisterReply>,
+    http_request_update: Option<CanisterReply>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CanisterReply {
+    Response(CanisterResponse),
+    Reject {
+        code: ScenarioRejectCode,
+        message: String,
+    },
+    TransportError(String),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanisterResponse {
+    #[serde(default = "default_status_code")]
+    status_code: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    body: String,
+    upgrade: Option<bool>,
+    /// Chunks that are returned by the streaming callback after the body.
+    #[serde(default)]
+    streamed_chunks: Vec<String>,
+    #[serde(default)]
+    certification: ScenarioCertification,
+}
+
+fn default_status_code() -> u16 {
+    200
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ScenarioCertification {
+    #[default]
+    None,
+    V1,
+    Skip,
+    ResponseOnly {
+        #[serde(default)]
+        excluded_headers: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ScenarioRejectCode {
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+}
+
+impl From<ScenarioRejectCode> for RejectCode {
+    fn from(reject_code: ScenarioRejectCode) -> Self {
+        match reject_code {
+            ScenarioRejectCode::SysFatal => RejectCode::SysFatal,
+            ScenarioRejectCode::SysTransient => RejectCode::SysTransient,
+            ScenarioRejectCode::DestinationInvalid => RejectCode::DestinationInvalid,
+            ScenarioRejectCode::CanisterReject => RejectCode::CanisterReject,
+            ScenarioRejectCode::CanisterError => RejectCode::CanisterError,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Expected {
+    status: u16,
+    /// Headers that must be present with the given values.
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    #[serde(default)]
+    absent_headers: Vec<String>,
+    body: Option<String>,
+    body_contains: Option<String>,
+    #[serde(default)]
+    metadata: ExpectedMetadata,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpectedMetadata {
+    upgraded_to_update_call: Option<bool>,
+    /// `null` expects that the response was not verified, a missing value is not checked.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    response_verification_version: Option<Option<u16>>,
+    internal_error: Option<bool>,
+}
+
+fn deserialize_some<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+/// The streaming types of the HTTP interface of canisters, with a concrete token type.
+#[derive(CandidType, Deserialize)]
+struct StreamingToken {
+    index: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingToken>,
+}
+
+define_function!(StreamingCallback : (StreamingToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize)]
+enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingToken,
+    },
+}
+
+#[derive(CandidType)]
+struct StreamingHttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+    upgrade: Option<bool>,
+}
+
+const STREAMING_CALLBACK_METHOD: &str = "http_request_streaming_callback";
+
+fn scenarios(target: Target) -> Vec<Scenario> {
+    serde_json::from_str::<Vec<Scenario>>(SCENARIOS)
+        .expect("the scenarios are valid")
+        .into_iter()
+        .filter(|scenario| scenario.targets.contains(&target))
+        .collect()
+}
+
+fn http_gateway_client(
+    builder: HttpGatewayClientBuilder,
+    client: &ScenarioClient,
+) -> HttpGatewayClient {
+    let mut streaming_limits = StreamingLimits::new();
+    if let Some(max_verified_callback_calls) = client.max_verified_callback_calls {
+        streaming_limits =
+            streaming_limits.with_max_verified_callback_calls(max_verified_callback_calls);
+    }
+    if let Some(max_body_bytes) = client.max_body_bytes {
+        streaming_limits = streaming_limits.with_max_body_bytes(max_body_bytes);
+    }
+
+    builder
+        .with_streaming_limits(streaming_limits)
+        .build()
+        .unwrap()
+}
+
+/// Sends the request of `scenario` and returns a description of every expectation that was not met.
+async fn run_scenario(
+    http_gateway: &HttpGatewayClient,
+    canister_id: Principal,
+    scenario: &Scenario,
+) -> Vec<String> {
+    let mut request = Request::builder()
+        .method(scenario.request.method.as_str())
+        .uri(scenario.request.url.as_str());
+    for (name, value) in &scenario.request.headers {
+        request = request.header(name, value);
+    }
+
+    let response = http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id,
+            canister_request: request
+                .body(scenario.request.body.as_bytes().to_vec())
+                .unwrap(),
+            client_info: None,
+            identity: None,
+        })
+        .send()
+        .await;
+
+    let expected = &scenario.expected;
+    let mut failures = vec![];
+
+    let status = response.canister_response.status().as_u16();
+    if status != expected.status {
+        failures.push(format!(
+            "expected status {}, got {}",
+            expected.status, status
+        ));
+    }
+
+    let headers = response.canister_response.headers();
+    for (name, value) in &expected.headers {
+        let actual = headers
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default());
+        if actual != Some(value.as_str()) {
+            failures.push(format!(
+                "expected header {}: {}, got {:?}",
+                name, value, actual
+            ));
+        }
+    }
+    for name in &expected.absent_headers {
+        if headers.contains_key(name) {
+            failures.push(format!("expected header {} to be absent", name));
+        }
+    }
+
+    let metadata = &response.metadata;
+    if let Some(upgraded_to_update_call) = expected.metadata.upgraded_to_update_call {
+        if metadata.upgraded_to_update_call != upgraded_to_update_call {
+            failures.push(format!(
+                "expected upgraded_to_update_call to be {}",
+                upgraded_to_update_call
+            ));
+        }
+    }
+    if let Some(response_verification_version) = expected.metadata.response_verification_version {
+        if metadata.response_verification_version != response_verification_version {
+            failures.push(format!(
+                "expected response verification version {:?}, got {:?}",
+                response_verification_version, metadata.response_verification_version
+            ));
+        }
+    }
+    if let Some(internal_error) = expected.metadata.internal_error {
+        if metadata.internal_error.is_some() != internal_error {
+            failures.push(format!(
+                "expected an internal error: {}, got {:?}",
+                internal_error, metadata.internal_error
+            ));
+        }
+    }
+
+    let body = match response.canister_response.into_body().collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).to_string(),
+        Err(e) => {
+            failures.push(format!("failed to read the body: {}", e));
+
+            return failures;
+        }
+    };
+    if let Some(expected_body) = &expected.body {
+        if body != *expected_body {
+            failures.push(format!("expected body {:?}, got {:?}", expected_body, body));
+        }
+    }
+    if let Some(body_contains) = &expected.body_contains {
+        if !body.contains(body_contains.as_str()) {
+            failures.push(format!(
+                "expected body to contain {:?}, got {:?}",
+                body_contains, body
+            ));
+        }
+    }
+
+    failures
+}
+
+/// Panics with the failures of all scenarios, grouped by the section of the specification.
+fn assert_conformance(target: Target, results: Vec<(&Scenario, Vec<String>)>) {
+    let failures = results
+        .into_iter()
+        .filter(|(_, failures)| !failures.is_empty())
+        .map(|(scenario, failures)| {
+            format!(
+                "[{}] {}:\n    {}",
+                scenario.section,
+                scenario.name,
+                failures.join("\n    ")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    assert!(
+        failures.is_empty(),
+        "{} scenarios failed against {:?}:\n{}",
+        failures.len(),
+        target,
+        failures.join("\n")
+    );
+}
+
+/// Scripts the fake replica to respond to the request of `scenario` as described by the scenario.
+fn script_canister(
+    replica: &FakeReplica,
+    fixture: &CertificationFixture,
+    canister_id: Principal,
+    scenario: &Scenario,
+) {
+    let request = HttpRequest {
+        method: scenario.request.method.clone(),
+        url: scenario.request.url.clone(),
+        headers: scenario.request.headers.clone(),
+        body: scenario.request.body.as_bytes().to_vec(),
+    };
+
+    for (method, reply) in [
+        ("http_request", &scenario.canister.http_request),
+        (
+            "http_request_update",
+            &scenario.canister.http_request_update,
+        ),
+    ] {
+        let Some(reply) = reply else {
+            continue;
+        };
+
+        let reply = match reply {
+            CanisterReply::Response(response) => {
+                script_streaming_callbacks(replica, canister_id, response);
+
+                fake_response(fixture, canister_id, &request, response)
+            }
+            CanisterReply::Reject { code, message } => {
+                FakeReply::reject((*code).into(), message.as_str())
+            }
+            CanisterReply::TransportError(message) => FakeReply::TransportError(message.clone()),
+        };
+
+        match method {
+            "http_request" => replica.push_query_reply(canister_id, method, reply),
+            _ => replica.push_update_reply(canister_id, method, reply),
+        }
+    }
+}
+
+fn fake_response(
+    fixture: &CertificationFixture,
+    canister_id: Principal,
+    request: &HttpRequest,
+    response: &CanisterResponse,
+) -> FakeReply {
+    // the certification covers the entire body, including the streamed chunks
+    let streamed_body = response.streamed_chunks.concat();
+    let certified_response = HttpResponse {
+        status_code: response.status_code,
+        headers: response.headers.clone(),
+        body: [response.body.as_bytes(), streamed_body.as_bytes()].concat(),
+        upgrade: response.upgrade,
+    };
+
+    let certification = match &response.certification {
+        ScenarioCertification::None => None,
+        ScenarioCertification::V1 => Some(FixtureCertification::V1),
+        ScenarioCertification::Skip => Some(FixtureCertification::Skip),
+        ScenarioCertification::ResponseOnly { excluded_headers } => {
+            Some(FixtureCertification::ResponseOnly(
+                DefaultCelBuilder::response_only_certification()
+                    .with_response_certification(
+                        DefaultResponseCertification::response_header_exclusions(
+                            excluded_headers.iter().map(String::as_str).collect(),
+                        ),
+                    )
+                    .build(),
+            ))
+        }
+    };
+    let certified_response = match certification {
+        Some(certification) => fixture.certify(request, certified_response, certification),
+        None => certified_response,
+    };
+
+    let streaming_strategy =
+        (!response.streamed_chunks.is_empty()).then(|| StreamingStrategy::Callback {
+            callback: StreamingCallback::new(canister_id, STREAMING_CALLBACK_METHOD.to_string()),
+            token: StreamingToken { index: 0 },
+        });
+
+    FakeReply::candid(&StreamingHttpResponse {
+        status_code: certified_response.status_code,
+        headers: certified_response.headers,
+        body: response.body.as_bytes().to_vec(),
+        streaming_strategy,
+        upgrade: certified_response.upgrade,
+    })
+}
+
+fn script_streaming_callbacks(
+    replica: &FakeReplica,
+    canister_id: Principal,
+    response: &CanisterResponse,
+) {
+    let chunks = &response.streamed_chunks;
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let token = (index + 1 < chunks.len()).then(|| StreamingToken {
+            index: index as u64 + 1,
+        });
+
+        replica.push_query_reply(
+            canister_id,
+            STREAMING_CALLBACK_METHOD,
+            FakeReply::candid(&StreamingCallbackHttpResponse {
+                body: chunk.as_bytes().to_vec(),
+                token,
+            }),
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_conformance_fake() {
+    let canister_id = Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap();
+    let scenarios = scenarios(Target::Fake);
+
+    let mut results = vec![];
+    for scenario in &scenarios {
+        let root_key = TestRootKey::default();
+        let replica = FakeReplica::new(root_key.clone());
+        let fixture = CertificationFixture::new(root_key, canister_id);
+        script_canister(&replica, &fixture, canister_id, scenario);
+
+        let http_gateway = http_gateway_client(
+            HttpGatewayClient::builder()
+                .with_agent(replica.agent())
+                .with_root_key(replica.root_key().root_key()),
+            &scenario.client,
+        );
+
+        results.push((
+            scenario,
+            run_scenario(&http_gateway, canister_id, scenario).await,
+        ));
+    }
+
+    assert_conformance(Target::Fake, results);
+}
+
+#[test]
+fn test_conformance_pocket_ic() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+    let pic = PocketIcBuilder::new()
+        .with_nns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let canister_id = pic.create_canister();
+    pic.add_cycles(canister_id, 2_000_000_000_000);
+    pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+    let url = pic.auto_progress();
+    let scenarios = scenarios(Target::PocketIc);
+
+    let results = rt.block_on(async {
+        let mut results = vec![];
+        for scenario in &scenarios {
+            let http_gateway = http_gateway_client(
+                HttpGatewayClient::builder()
+                    .with_endpoint_pool(EndpointPool::new([url.to_string()]).unwrap())
+                    .with_root_key(RootKey::InsecureFetchFromDevNetwork),
+                &scenario.client,
+            );
+
+            results.push((
+                scenario,
+                run_scenario(&http_gateway, canister_id, scenario).await,
+            ));
+        }
+
+        results
+    });
+
+    assert_conformance(Target::PocketIc, results);
+}
@@ -1,7 +1,8 @@
 use http::Request;
 use http_body_util::BodyExt;
-use ic_agent::Agent;
-use ic_http_gateway::{HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata};
+use ic_http_gateway::{
+    EndpointPool, HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponseMetadata, RootKey,
+};
 use pocket_ic::PocketIcBuilder;
 
 mod utils;
@@ -22,13 +23,9 @@ fn test_custom_assets_index_html() {
 
     let url = pic.auto_progress();
 
-    let agent = Agent::builder().with_url(url).build().unwrap();
-    rt.block_on(async {
-        agent.fetch_root_key().await.unwrap();
-    });
-
     let http_gateway = HttpGatewayClient::builder()
-        .with_agent(agent)
+        .with_endpoint_pool(EndpointPool::new([url]).unwrap())
+        .with_root_key(RootKey::InsecureFetchFromDevNetwork)
         .build()
         .unwrap();
 
@@ -37,6 +34,8 @@ fn test_custom_assets_index_html() {
             .request(HttpGatewayRequestArgs {
                 canister_id,
                 canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+                client_info: None,
+                identity: None,
             })
             .send()
             .await
@@ -104,8 +103,15 @@ fn test_custom_assets_index_html() {
         response.metadata,
         HttpGatewayResponseMetadata {
             upgraded_to_update_call: false,
+            direct_update_call: false,
             response_verification_version: Some(2),
+            update_response_verified: false,
+            query_signature_verified: false,
             internal_error: None,
+            endpoint: None,
+            subnet_endpoint: None,
+            failed_endpoints: vec![],
+            invalid_header_values: vec![],
         },
     );
 }
@@ -118,12 +124,180 @@ fn assert_response_metadata(
         response_metadata.upgraded_to_update_call,
         expected_response_metadata.upgraded_to_update_call
     );
+    assert_eq!(
+        response_metadata.direct_update_call,
+        expected_response_metadata.direct_update_call
+    );
     assert_eq!(
         response_metadata.response_verification_version,
         expected_response_metadata.response_verification_version
     );
+    assert_eq!(
+        response_metadata.update_response_verified,
+        expected_response_metadata.update_response_verified
+    );
+    assert_eq!(
+        response_metadata.query_signature_verified,
+        expected_response_metadata.query_signature_verified
+    );
+    assert_eq!(
+        response_metadata.invalid_header_values,
+        expected_response_metadata.invalid_header_values
+    );
 }
 
 fn contains_header(header_name: &str, headers: Vec<(&str, &str)>) -> bool {
     headers.iter().any(|(key, _)| *key == header_name)
 }
+
+struct CustomAssetsCanister {
+    rt: tokio::runtime::Runtime,
+    // keeps the PocketIC instance alive for the duration of the test
+    _pic: pocket_ic::PocketIc,
+    canister_id: candid::Principal,
+    http_gateway: HttpGatewayClient,
+}
+
+impl CustomAssetsCanister {
+    fn install() -> Self {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let wasm_bytes = rt.block_on(async { utils::load_custom_assets_wasm().await });
+
+        let pic = PocketIcBuilder::new()
+            .with_nns_subnet()
+            .with_application_subnet()
+            .build();
+
+        let canister_id = pic.create_canister();
+        pic.add_cycles(canister_id, 2_000_000_000_000);
+        pic.install_canister(canister_id, wasm_bytes, vec![], None);
+
+        let url = pic.auto_progress();
+
+        let http_gateway = HttpGatewayClient::builder()
+            .with_endpoint_pool(EndpointPool::new([url]).unwrap())
+            .with_root_key(RootKey::InsecureFetchFromDevNetwork)
+            .build()
+            .unwrap();
+
+        Self {
+            rt,
+            _pic: pic,
+            canister_id,
+            http_gateway,
+        }
+    }
+
+    fn request(
+        &self,
+        canister_request: Request<Vec<u8>>,
+    ) -> (
+        http::StatusCode,
+        http::HeaderMap,
+        Vec<u8>,
+        HttpGatewayResponseMetadata,
+    ) {
+        self.rt.block_on(async {
+            let response = self
+                .http_gateway
+                .request(HttpGatewayRequestArgs {
+                    canister_id: self.canister_id,
+                    canister_request,
+                    client_info: None,
+                    identity: None,
+                })
+                .send()
+                .await;
+
+            let status = response.canister_response.status();
+            let headers = response.canister_response.headers().clone();
+            let body = response
+                .canister_response
+                .into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes()
+                .to_vec();
+
+            (status, headers, body, response.metadata)
+        })
+    }
+}
+
+#[test]
+fn test_custom_assets_fallback() {
+    let canister = CustomAssetsCanister::install();
+
+    let (status, _, body, metadata) =
+        canister.request(Request::builder().uri("/missing").body(vec![]).unwrap());
+
+    assert_eq!(status, 200);
+    assert_eq!(body, b"<html><body>Hello, world!</body></html>");
+    assert_eq!(metadata.response_verification_version, Some(2));
+}
+
+#[test]
+fn test_custom_assets_encoded_variants() {
+    let canister = CustomAssetsCanister::install();
+
+    let (status, headers, body, metadata) = canister.request(
+        Request::builder()
+            .uri("/encoded")
+            .header("accept-encoding", "gzip, deflate")
+            .body(vec![])
+            .unwrap(),
+    );
+
+    assert_eq!(status, 200);
+    assert_eq!(headers.get("content-encoding").unwrap(), "gzip");
+    assert_eq!(&body[..2], b"\x1f\x8b");
+    assert_eq!(metadata.response_verification_version, Some(2));
+
+    let (status, headers, body, metadata) =
+        canister.request(Request::builder().uri("/encoded").body(vec![]).unwrap());
+
+    assert_eq!(status, 200);
+    assert!(headers.get("content-encoding").is_none());
+    assert_eq!(body, b"<html><body>Hello, encoded world!</body></html>");
+    assert_eq!(metadata.response_verification_version, Some(2));
+}
+
+#[test]
+fn test_custom_assets_large_streamed_asset() {
+    let canister = CustomAssetsCanister::install();
+
+    let (status, _, body, metadata) = canister.request(
+        Request::builder()
+            .uri("/stream/large")
+            .body(vec![])
+            .unwrap(),
+    );
+
+    assert_eq!(status, 200);
+    assert_eq!(body.len(), 6 * 256 * 1024);
+    for (index, chunk) in body.chunks(256 * 1024).enumerate() {
+        assert!(chunk.iter().all(|byte| *byte == index as u8));
+    }
+    // the asset is streamed with more callbacks than are verified by default
+    assert_eq!(metadata.response_verification_version, None);
+}
+
+#[test]
+fn test_custom_assets_reject_and_trap() {
+    let canister = CustomAssetsCanister::install();
+
+    let (status, _, body, metadata) =
+        canister.request(Request::builder().uri("/reject").body(vec![]).unwrap());
+
+    assert_eq!(status, 502);
+    assert!(String::from_utf8_lossy(&body).contains("rejected by the canister"));
+    assert!(metadata.internal_error.is_some());
+
+    let (status, _, body, metadata) =
+        canister.request(Request::builder().uri("/trap").body(vec![]).unwrap());
+
+    assert_eq!(status, 502);
+    assert!(String::from_utf8_lossy(&body).contains("trapped by the canister"));
+    assert!(metadata.internal_error.is_some());
+}
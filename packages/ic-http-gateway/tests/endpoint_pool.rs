@@ -0,0 +1,159 @@
+use candid::Principal;
+use http::Request;
+use ic_http_gateway::{
+    EndpointPool, HttpGatewayClient, HttpGatewayRequestArgs, RootKey, StaticSubnetRoutingProvider,
+    SubnetRoute, SubnetRouting,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// The CBOR encoding of `{"ic_api_version": "0.18.0", "replica_health_status": "healthy"}`.
+const STATUS_BODY: &[u8] = b"\xa2\x6eic_api_version\x660.18.0\x75replica_health_status\x67healthy";
+
+/// Starts a local stand-in for an API boundary node that answers every request with its status.
+async fn start_stand_in() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                read_request(&mut socket).await;
+
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/cbor\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    STATUS_BODY.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(STATUS_BODY).await;
+            });
+        }
+    });
+
+    url
+}
+
+/// Reads the head and the body of a request, so that closing the connection does not reset it.
+async fn read_request(socket: &mut TcpStream) {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+
+    loop {
+        let Ok(n @ 1..) = socket.read(&mut buf).await else {
+            return;
+        };
+        request.extend_from_slice(&buf[..n]);
+
+        let Some(head_len) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&request[..head_len]).to_lowercase();
+        let content_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+
+        if request.len() >= head_len + 4 + content_length {
+            return;
+        }
+    }
+}
+
+/// Returns the URL of a local port that refuses connections.
+async fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+#[tokio::test]
+async fn test_endpoint_pool_fails_over_on_transport_errors() {
+    let unreachable = unreachable_url().await;
+    let stand_in = start_stand_in().await;
+
+    let http_gateway = HttpGatewayClient::builder()
+        .with_endpoint_pool(EndpointPool::new([unreachable.clone(), stand_in.clone()]).unwrap())
+        .with_root_key(RootKey::from_bytes(&[7; 96]).unwrap())
+        .build()
+        .unwrap();
+
+    let response = http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id: Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap(),
+            canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+            client_info: None,
+            identity: None,
+        })
+        .send()
+        .await;
+
+    // the stand-in does not answer the query call, but it is reachable, so there is no further failover
+    assert!(response.metadata.internal_error.is_some());
+    assert_eq!(response.metadata.endpoint, Some(stand_in));
+    assert_eq!(response.metadata.failed_endpoints, vec![unreachable]);
+}
+
+#[tokio::test]
+async fn test_endpoint_pool_routes_to_healthy_endpoints() {
+    let unreachable = unreachable_url().await;
+    let stand_in = start_stand_in().await;
+
+    let endpoint_pool = EndpointPool::new([unreachable, stand_in.clone()]).unwrap();
+    endpoint_pool.check_health().await;
+
+    let http_gateway = HttpGatewayClient::builder()
+        .with_endpoint_pool(endpoint_pool)
+        .with_root_key(RootKey::from_bytes(&[7; 96]).unwrap())
+        .build()
+        .unwrap();
+
+    let response = http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id: Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap(),
+            canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+            client_info: None,
+            identity: None,
+        })
+        .send()
+        .await;
+
+    assert_eq!(response.metadata.endpoint, Some(stand_in));
+    assert!(response.metadata.failed_endpoints.is_empty());
+}
+
+#[tokio::test]
+async fn test_subnet_routing_sends_queries_to_subnet_nodes() {
+    let endpoint = start_stand_in().await;
+    let node = start_stand_in().await;
+    let canister_id = Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap();
+
+    let http_gateway = HttpGatewayClient::builder()
+        .with_endpoint_pool(EndpointPool::new([endpoint.clone()]).unwrap())
+        .with_root_key(RootKey::from_bytes(&[7; 96]).unwrap())
+        .with_subnet_routing(SubnetRouting::new(StaticSubnetRoutingProvider::new(vec![
+            SubnetRoute {
+                subnet_id: Principal::from_slice(&[1; 29]),
+                canister_ranges: vec![(canister_id, canister_id)],
+                endpoints: vec![node.clone()],
+            },
+        ])))
+        .build()
+        .unwrap();
+
+    let response = http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id,
+            canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+            client_info: None,
+            identity: None,
+        })
+        .send()
+        .await;
+
+    assert_eq!(response.metadata.endpoint, Some(endpoint));
+    assert_eq!(response.metadata.subnet_endpoint, Some(node));
+}
@@ -0,0 +1,244 @@
+use candid::{CandidType, Principal};
+use http::Request;
+use http_body_util::BodyExt;
+use ic_agent::agent::RejectCode;
+use ic_http_gateway::{
+    testing::{FakeReplica, FakeReply},
+    DirectUpdateCalls, HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponse,
+    UpgradePolicy, UpgradeRule,
+};
+use std::time::Duration;
+
+/// The `HttpResponse` type of the HTTP interface of canisters, without a streaming strategy.
+#[derive(CandidType)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>,
+}
+
+fn canister_id() -> Principal {
+    Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+}
+
+fn http_response(body: &[u8], upgrade: Option<bool>) -> FakeReply {
+    FakeReply::candid(&HttpResponse {
+        status_code: 200,
+        headers: vec![("content-type".to_string(), "text/plain".to_string())],
+        body: body.to_vec(),
+        upgrade,
+    })
+}
+
+async fn send(replica: &FakeReplica, skip_verification: bool) -> HttpGatewayResponse {
+    let http_gateway = HttpGatewayClient::builder()
+        .with_agent(replica.agent())
+        .with_root_key(replica.root_key().root_key())
+        .build()
+        .unwrap();
+
+    let mut request = http_gateway.request(HttpGatewayRequestArgs {
+        canister_id: canister_id(),
+        canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+        client_info: None,
+        identity: None,
+    });
+    request.unsafe_set_skip_verification(skip_verification);
+
+    request.send().await
+}
+
+async fn body(response: HttpGatewayResponse) -> Vec<u8> {
+    response
+        .canister_response
+        .into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes()
+        .to_vec()
+}
+
+#[tokio::test]
+async fn test_query_reply() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(canister_id(), "http_request", http_response(b"hello", None));
+
+    let response = send(&replica, true).await;
+
+    assert_eq!(response.canister_response.status(), 200);
+    assert!(!response.metadata.upgraded_to_update_call);
+    assert!(!response.metadata.query_signature_verified);
+    assert_eq!(body(response).await, b"hello");
+
+    let requests = replica.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].request_type, "query");
+    assert_eq!(requests[0].method_name.as_deref(), Some("http_request"));
+}
+
+#[tokio::test]
+async fn test_query_signatures_are_verified() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(canister_id(), "http_request", http_response(b"hello", None));
+    let http_gateway = HttpGatewayClient::builder()
+        .with_agent(replica.agent())
+        .with_root_key(replica.root_key().root_key())
+        .with_verify_query_signatures(true)
+        .build()
+        .unwrap();
+
+    let mut request = http_gateway.request(HttpGatewayRequestArgs {
+        canister_id: canister_id(),
+        canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+        client_info: None,
+        identity: None,
+    });
+    request.unsafe_set_skip_verification(true);
+    let response = request.send().await;
+
+    assert!(response.metadata.internal_error.is_none());
+    assert!(response.metadata.query_signature_verified);
+    assert_eq!(body(response).await, b"hello");
+
+    let request_types = replica
+        .requests()
+        .into_iter()
+        .map(|request| request.request_type)
+        .collect::<Vec<_>>();
+    assert!(request_types.contains(&"query".to_string()));
+    assert!(request_types.contains(&"read_state".to_string()));
+}
+
+#[tokio::test]
+async fn test_uncertified_query_reply_is_rejected() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(canister_id(), "http_request", http_response(b"hello", None));
+
+    let response = send(&replica, false).await;
+
+    assert_eq!(response.canister_response.status(), 500);
+    assert!(response.metadata.internal_error.is_some());
+}
+
+#[tokio::test]
+async fn test_upgrade_to_update_call() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(
+        canister_id(),
+        "http_request",
+        http_response(b"", Some(true)),
+    );
+    replica.push_update_reply(
+        canister_id(),
+        "http_request_update",
+        http_response(b"updated", None),
+    );
+
+    let response = send(&replica, false).await;
+
+    assert_eq!(response.canister_response.status(), 200);
+    assert!(response.metadata.upgraded_to_update_call);
+    assert_eq!(body(response).await, b"updated");
+
+    let request_types = replica
+        .requests()
+        .into_iter()
+        .map(|request| request.request_type)
+        .collect::<Vec<_>>();
+    assert_eq!(request_types[..2], ["query", "call"]);
+    assert!(request_types[2..]
+        .iter()
+        .all(|request_type| request_type == "read_state"));
+}
+
+#[tokio::test]
+async fn test_denied_upgrade_is_not_called_directly() {
+    let replica = FakeReplica::default();
+    let http_gateway = HttpGatewayClient::builder()
+        .with_agent(replica.agent())
+        .with_root_key(replica.root_key().root_key())
+        .with_upgrade_policy(UpgradePolicy::new().with_default_rule(UpgradeRule::Never))
+        .with_direct_update_calls(
+            DirectUpdateCalls::new().with_adaptive_cache(10, Duration::from_secs(60)),
+        )
+        .build()
+        .unwrap();
+
+    for _ in 0..2 {
+        replica.push_query_reply(
+            canister_id(),
+            "http_request",
+            http_response(b"", Some(true)),
+        );
+
+        let response = http_gateway
+            .request(HttpGatewayRequestArgs {
+                canister_id: canister_id(),
+                canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+                client_info: None,
+                identity: None,
+            })
+            .send()
+            .await;
+
+        assert_eq!(response.canister_response.status(), 403);
+        assert!(!response.metadata.direct_update_call);
+    }
+
+    let request_types = replica
+        .requests()
+        .into_iter()
+        .map(|request| request.request_type)
+        .collect::<Vec<_>>();
+    assert_eq!(request_types, ["query", "query"]);
+}
+
+#[tokio::test]
+async fn test_rejected_update_call() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(
+        canister_id(),
+        "http_request",
+        http_response(b"", Some(true)),
+    );
+    replica.push_update_reply(
+        canister_id(),
+        "http_request_update",
+        FakeReply::reject(RejectCode::CanisterError, "trapped"),
+    );
+
+    let response = send(&replica, false).await;
+
+    assert_eq!(response.canister_response.status(), 502);
+}
+
+#[tokio::test]
+async fn test_query_rejected_with_destination_invalid() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(
+        canister_id(),
+        "http_request",
+        FakeReply::reject(RejectCode::DestinationInvalid, "canister not found"),
+    );
+
+    let response = send(&replica, false).await;
+
+    assert_eq!(response.canister_response.status(), 404);
+}
+
+#[tokio::test]
+async fn test_query_transport_error() {
+    let replica = FakeReplica::default();
+    replica.push_query_reply(
+        canister_id(),
+        "http_request",
+        FakeReply::TransportError("connection refused".to_string()),
+    );
+
+    let response = send(&replica, false).await;
+
+    assert_eq!(response.canister_response.status(), 500);
+    assert!(response.metadata.internal_error.is_some());
+}
@@ -0,0 +1,85 @@
+use candid::Principal;
+use http::Request;
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultResponseCertification, HttpRequest, HttpResponse,
+};
+use ic_http_gateway::{
+    testing::{CertificationFixture, FakeReplica, FakeReply, FixtureCertification, TestRootKey},
+    HttpGatewayClient, HttpGatewayRequestArgs, HttpGatewayResponse,
+};
+
+fn canister_id() -> Principal {
+    Principal::from_text("qoctq-giaaa-aaaaa-aaaea-cai").unwrap()
+}
+
+fn redirect() -> HttpResponse {
+    HttpResponse {
+        status_code: 302,
+        headers: vec![("location".to_string(), "https://example.com".to_string())],
+        body: vec![],
+        upgrade: None,
+    }
+}
+
+/// Serves `response` from the fake replica, certified for a `GET` request to `/`.
+async fn send_certified(
+    response: HttpResponse,
+    certification: FixtureCertification<'_>,
+) -> HttpGatewayResponse {
+    let root_key = TestRootKey::default();
+    let replica = FakeReplica::new(root_key.clone());
+    let request = HttpRequest {
+        method: "GET".to_string(),
+        url: "/".to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    let response = CertificationFixture::new(root_key, canister_id()).certify(
+        &request,
+        response,
+        certification,
+    );
+    replica.push_query_reply(canister_id(), "http_request", FakeReply::candid(&response));
+
+    let http_gateway = HttpGatewayClient::builder()
+        .with_agent(replica.agent())
+        .with_root_key(replica.root_key().root_key())
+        .build()
+        .unwrap();
+
+    http_gateway
+        .request(HttpGatewayRequestArgs {
+            canister_id: canister_id(),
+            canister_request: Request::builder().uri("/").body(vec![]).unwrap(),
+            client_info: None,
+            identity: None,
+        })
+        .send()
+        .await
+}
+
+#[tokio::test]
+async fn test_v1_redirects_are_rejected() {
+    let response = send_certified(redirect(), FixtureCertification::V1).await;
+
+    assert_eq!(response.canister_response.status(), 500);
+    assert_eq!(response.metadata.response_verification_version, Some(1));
+}
+
+#[tokio::test]
+async fn test_v2_redirects_are_allowed() {
+    let cel_expr = DefaultCelBuilder::response_only_certification()
+        .with_response_certification(DefaultResponseCertification::response_header_exclusions(
+            vec![],
+        ))
+        .build();
+
+    let response = send_certified(redirect(), FixtureCertification::ResponseOnly(cel_expr)).await;
+
+    assert_eq!(response.canister_response.status(), 302);
+    assert_eq!(
+        response.canister_response.headers()["location"],
+        "https://example.com"
+    );
+    assert_eq!(response.metadata.response_verification_version, Some(2));
+}